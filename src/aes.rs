@@ -0,0 +1,86 @@
+//! WinZip AE-2 encryption with AES-256. The compressed data is encrypted with AES in counter mode
+//! using a key derived from the password and a random salt, and authenticated with HMAC-SHA1.
+
+use std::io::{Read, Write};
+
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes256,
+};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::{CompressionType, Password};
+
+pub(crate) const AES_EXTRA_FIELD_ID: u16 = 0x9901;
+/// Compression method of every AES encrypted entry, the real one is in the extra field
+pub(crate) const AES_COMPRESSION_METHOD: u16 = 99;
+pub(crate) const AES_VERSION_NEEDED_TO_EXTRACT: u16 = 51;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const PASSWORD_VERIFIER_LEN: usize = 2;
+const AUTHENTICATION_CODE_LEN: usize = 10;
+const KEY_DERIVATION_ITERATIONS: u32 = 1000;
+/// AE-2, which leaves the CRC out of the headers
+const AE_VERSION: u16 = 2;
+const AES_256_STRENGTH: u8 = 3;
+/// Bytes that encryption adds to the compressed data
+pub(crate) const OVERHEAD: usize = SALT_LEN + PASSWORD_VERIFIER_LEN + AUTHENTICATION_CODE_LEN;
+
+/// Counter mode as WinZip does it, with a little endian counter starting at 1
+type Aes256Ctr = ctr::Ctr128LE<Aes256>;
+
+/// Encrypt compressed data from `reader` into `output`. The result starts with the salt and the
+/// password verifier and ends with the authentication code.
+pub(crate) fn encrypt<R: Read, W: Write + ?Sized>(
+    mut reader: R,
+    output: &mut W,
+    password: &Password,
+) -> Result<(), std::io::Error> {
+    let mut salt = [0; SALT_LEN];
+    getrandom::fill(&mut salt)?;
+    let mut keys = [0; 2 * KEY_LEN + PASSWORD_VERIFIER_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha1>(
+        password.0.as_bytes(),
+        &salt,
+        KEY_DERIVATION_ITERATIONS,
+        &mut keys,
+    );
+    let (encryption_key, rest) = keys.split_at(KEY_LEN);
+    let (authentication_key, password_verifier) = rest.split_at(KEY_LEN);
+
+    let mut iv = [0; 16];
+    iv[0] = 1;
+    let mut cipher = Aes256Ctr::new(encryption_key.into(), &iv.into());
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha1>::new_from_slice(authentication_key).unwrap();
+
+    output.write_all(&salt)?;
+    output.write_all(password_verifier)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let chunk = &mut buf[..read];
+        cipher.apply_keystream(chunk);
+        mac.update(chunk);
+        output.write_all(chunk)?;
+    }
+    output.write_all(&mac.finalize().into_bytes()[..AUTHENTICATION_CODE_LEN])?;
+    Ok(())
+}
+
+/// Write the AES extra field, which holds the real compression method of the entry
+pub(crate) fn write_extra_field(buf: &mut Vec<u8>, compression_type: CompressionType) {
+    buf.extend_from_slice(&AES_EXTRA_FIELD_ID.to_le_bytes());
+    buf.extend_from_slice(&7_u16.to_le_bytes());
+    buf.extend_from_slice(&AE_VERSION.to_le_bytes());
+    buf.extend_from_slice(b"AE");
+    buf.push(AES_256_STRENGTH);
+    buf.extend_from_slice(&(compression_type as u16).to_le_bytes());
+}
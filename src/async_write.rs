@@ -0,0 +1,81 @@
+//! Writing archives to tokio's asynchronous writers
+
+use std::io::Write;
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{ArchiveStats, ZipArchive, ZipError};
+
+/// Size of the chunks the archive is sent to the runtime in
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks that can wait for the destination before writing the archive blocks
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+impl ZipArchive<'static> {
+    /// Wait for all files to be compressed and write the archive to an asynchronous destination,
+    /// without blocking the runtime. Waiting and serialization happen on tokio's blocking thread
+    /// pool, and the archive is passed to `destination` in chunks, so it's never held in memory
+    /// as a whole. Requires the `tokio` feature and has to be called from within a tokio runtime.
+    ///
+    /// The archive is written like [`ZipArchive::write_streaming`] writes it, with data
+    /// descriptors. If the runtime shuts down before the archive is written, an I/O error of kind
+    /// [`std::io::ErrorKind::Interrupted`] is returned.
+    pub async fn write_async<W: AsyncWrite + Unpin>(
+        self,
+        destination: &mut W,
+    ) -> Result<ArchiveStats, ZipError> {
+        let (tx, mut rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+        let writer = tokio::task::spawn_blocking(move || {
+            let mut sender = ChunkSender {
+                tx,
+                buf: Vec::with_capacity(CHUNK_SIZE),
+            };
+            let stats = self.write_streaming(&mut sender)?;
+            sender.flush()?;
+            Ok(stats)
+        });
+        // If writing fails, dropping the receiver makes the blocking side stop at the next chunk
+        while let Some(chunk) = rx.recv().await {
+            destination.write_all(&chunk).await?;
+        }
+        destination.flush().await?;
+        match writer.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // The runtime is shutting down and cancelled the task before it started
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Interrupted, e).into()),
+        }
+    }
+}
+
+/// Writer that sends what is written to it to the runtime in chunks
+struct ChunkSender {
+    tx: mpsc::Sender<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx.blocking_send(chunk).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "writing to the destination failed",
+            )
+        })
+    }
+}
@@ -0,0 +1,226 @@
+//! Compression methods. Every method is an [`Encoder`], and [`encoder`] picks the one for the
+//! settings of an entry, so adding a method only touches this module and [`CompressionType`].
+
+#[cfg(feature = "deflate-dictionary")]
+use std::io::ErrorKind;
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateEncoder, Compress, Compression, FlushCompress, Status};
+use rayon::prelude::*;
+
+use crate::{CompressionType, EntrySettings};
+
+/// Compression method that entry data goes through. The CRC and size of the uncompressed data
+/// are computed by the caller.
+pub(crate) trait Encoder {
+    /// Compress everything from `reader` into `output`
+    fn encode(&self, reader: &mut dyn Read, output: &mut dyn Write) -> Result<(), std::io::Error>;
+}
+
+/// Encoder for the compression type and level of `settings`
+pub(crate) fn encoder(settings: &EntrySettings) -> Box<dyn Encoder + '_> {
+    let level = settings.compression_level;
+    match settings.compression_type {
+        CompressionType::Stored => Box::new(Store),
+        #[cfg(feature = "deflate-dictionary")]
+        CompressionType::Deflate if settings.deflate_dictionary.is_some() => {
+            Box::new(DictionaryDeflate {
+                level,
+                dictionary: settings.deflate_dictionary.as_deref().unwrap(),
+            })
+        }
+        CompressionType::Deflate => match settings.deflate_chunk_size {
+            Some(chunk_size) => Box::new(ChunkedDeflate { level, chunk_size }),
+            None => Box::new(Deflate { level }),
+        },
+        // bzip2 has no level 0
+        #[cfg(feature = "bzip2")]
+        CompressionType::Bzip2 => Box::new(Bzip2 {
+            level: bzip2::Compression::new(level.level().max(1)),
+        }),
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => Box::new(Zstd {
+            level: level.level() as i32,
+        }),
+    }
+}
+
+struct Store;
+
+impl Encoder for Store {
+    fn encode(&self, reader: &mut dyn Read, output: &mut dyn Write) -> Result<(), std::io::Error> {
+        std::io::copy(reader, output)?;
+        Ok(())
+    }
+}
+
+struct Deflate {
+    level: Compression,
+}
+
+impl Encoder for Deflate {
+    fn encode(&self, reader: &mut dyn Read, output: &mut dyn Write) -> Result<(), std::io::Error> {
+        std::io::copy(&mut DeflateEncoder::new(reader, self.level), output)?;
+        Ok(())
+    }
+}
+
+/// Deflate that splits the data into chunks compressed in parallel, see [`deflate_chunked`]
+struct ChunkedDeflate {
+    level: Compression,
+    chunk_size: usize,
+}
+
+impl Encoder for ChunkedDeflate {
+    fn encode(&self, reader: &mut dyn Read, output: &mut dyn Write) -> Result<(), std::io::Error> {
+        deflate_chunked(reader, output, self.level, self.chunk_size)
+    }
+}
+
+#[cfg(feature = "deflate-dictionary")]
+struct DictionaryDeflate<'d> {
+    level: Compression,
+    dictionary: &'d [u8],
+}
+
+#[cfg(feature = "deflate-dictionary")]
+impl Encoder for DictionaryDeflate<'_> {
+    fn encode(&self, reader: &mut dyn Read, output: &mut dyn Write) -> Result<(), std::io::Error> {
+        deflate_with_dictionary(reader, output, self.level, self.dictionary)
+    }
+}
+
+#[cfg(feature = "bzip2")]
+struct Bzip2 {
+    level: bzip2::Compression,
+}
+
+#[cfg(feature = "bzip2")]
+impl Encoder for Bzip2 {
+    fn encode(&self, reader: &mut dyn Read, output: &mut dyn Write) -> Result<(), std::io::Error> {
+        std::io::copy(&mut bzip2::read::BzEncoder::new(reader, self.level), output)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+struct Zstd {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Encoder for Zstd {
+    fn encode(&self, reader: &mut dyn Read, output: &mut dyn Write) -> Result<(), std::io::Error> {
+        let mut encoder = zstd::stream::write::Encoder::new(output, self.level)?;
+        std::io::copy(reader, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+/// Deflate `reader` in chunks that are compressed in parallel on the current thread pool. Every
+/// chunk is a separate deflate stream that ends with a sync flush instead of a final block, except
+/// for the last one, so together they decompress as a single stream.
+fn deflate_chunked(
+    reader: &mut dyn Read,
+    output: &mut dyn Write,
+    level: Compression,
+    chunk_size: usize,
+) -> Result<(), std::io::Error> {
+    let batch_len = rayon::current_num_threads();
+    loop {
+        let mut chunks = Vec::with_capacity(batch_len);
+        let mut last = false;
+        while chunks.len() < batch_len && !last {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            (&mut *reader)
+                .take(chunk_size as u64)
+                .read_to_end(&mut chunk)?;
+            last = chunk.len() < chunk_size;
+            chunks.push(chunk);
+        }
+        let last_index = chunks.len() - 1;
+        let compressed = chunks
+            .par_iter()
+            .enumerate()
+            .map(|(i, chunk)| deflate_chunk(chunk, level, last && i == last_index))
+            .collect::<Result<Vec<_>, _>>()?;
+        for chunk in compressed {
+            output.write_all(&chunk)?;
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Deflate `chunk` as one part of [`deflate_chunked`]'s output
+fn deflate_chunk(chunk: &[u8], level: Compression, last: bool) -> Result<Vec<u8>, std::io::Error> {
+    let mut compress = Compress::new(level, false);
+    let flush = if last {
+        FlushCompress::Finish
+    } else {
+        FlushCompress::Sync
+    };
+    let mut output = Vec::with_capacity(chunk.len() / 2 + 64);
+    loop {
+        if output.len() == output.capacity() {
+            output.reserve(output.capacity());
+        }
+        let input = &chunk[compress.total_in() as usize..];
+        let status = compress.compress_vec(input, &mut output, flush)?;
+        let done = match status {
+            Status::StreamEnd => true,
+            // A sync flush is complete once all input is consumed and there was room to spare
+            _ => {
+                !last
+                    && compress.total_in() as usize == chunk.len()
+                    && output.len() < output.capacity()
+            }
+        };
+        if done {
+            return Ok(output);
+        }
+    }
+}
+
+/// Deflate everything from `reader` into `output` with a preset dictionary
+#[cfg(feature = "deflate-dictionary")]
+fn deflate_with_dictionary(
+    reader: &mut dyn Read,
+    output: &mut dyn Write,
+    level: Compression,
+    dictionary: &[u8],
+) -> Result<(), std::io::Error> {
+    let mut compress = Compress::new(level, false);
+    compress
+        .set_dictionary(dictionary)
+        .map_err(std::io::Error::other)?;
+    let mut input = vec![0; 64 * 1024];
+    let mut compressed = Vec::with_capacity(64 * 1024);
+    loop {
+        let read = match reader.read(&mut input) {
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let flush = if read == 0 {
+            FlushCompress::Finish
+        } else {
+            FlushCompress::None
+        };
+        let start = compress.total_in();
+        loop {
+            let consumed = (compress.total_in() - start) as usize;
+            let status = compress.compress_vec(&input[consumed..read], &mut compressed, flush)?;
+            output.write_all(&compressed)?;
+            compressed.clear();
+            if status == Status::StreamEnd {
+                return Ok(());
+            }
+            if read != 0 && compress.total_in() - start == read as u64 {
+                break;
+            }
+        }
+    }
+}
@@ -1,266 +1,4179 @@
 use std::{
-    fs::File,
-    io::{Read, Seek, Write},
-    path::Path,
-    sync::mpsc::{channel, Receiver, Sender},
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{File, Metadata},
+    io::{BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use flate2::{read::DeflateEncoder, Compression, CrcReader};
-use rayon::ThreadPool;
+use encoder::encoder;
+use flate2::{read::DeflateDecoder, Compression, Crc, CrcReader};
+use rayon::{prelude::*, ThreadPool};
+use read::{CentralDirectory, CentralDirectoryEntry};
+
+#[cfg(feature = "aes")]
+mod aes;
+#[cfg(feature = "tokio")]
+mod async_write;
+mod encoder;
+mod read;
+#[cfg(test)]
+mod tests;
+#[cfg(feature = "zipcrypto")]
+mod zipcrypto;
 
 const VERSION_NEEDED_TO_EXTRACT: u16 = 20;
-const VERSION_MADE_BY: u16 = 0x033F;
+/// Version of the specification that archives are written by default, 6.3
+const SPEC_VERSION: u8 = 63;
+
+const DEFAULT_COMPRESSION_LEVEL: u32 = 9;
+const MAX_COMPRESSION_LEVEL: u32 = 9;
+/// Smallest chunk that a file is split into for parallel deflate
+const MIN_DEFLATE_CHUNK_SIZE: usize = 64 * 1024;
+/// Smallest volume of a split archive allowed by the specification
+const MIN_VOLUME_SIZE: u64 = 64 * 1024;
+/// Central directory entries serialized at a time, in parallel for archives with at least as
+/// many entries
+const DIRECTORY_CHUNK_ENTRIES: usize = 4096;
+
+/// Regular file with 644 permissions
+const DEFAULT_FILE_ATTRIBUTES: u32 = 0o100644 << 16;
+/// Directory with 755 permissions
+const DIRECTORY_ATTRIBUTES: u32 = 0o40755 << 16;
+/// Unix symlink with rwxrwxrwx permissions, in the upper 16 bits
+const SYMLINK_ATTRIBUTES: u32 = 0o120777 << 16;
+/// Regular file with 755 permissions
+const EXECUTABLE_FILE_ATTRIBUTES: u32 = 0o100755 << 16;
+/// MS-DOS attributes, stored in the low 16 bits of the external file attributes
+const DOS_READ_ONLY: u32 = 0x01;
+const DOS_DIRECTORY: u32 = 0x10;
+const DOS_ARCHIVE: u32 = 0x20;
+/// Timestamp of entries in reproducible archives, 1980-01-01 00:00:00 UTC. It's the earliest
+/// MS-DOS timestamp, so it's stored exactly.
+const REPRODUCIBLE_TIMESTAMP: u64 = 315_532_800;
+
+/// CRC and sizes are in a data descriptor after the data instead of the local file header
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+/// Entry is encrypted
+const FLAG_ENCRYPTED: u16 = 1;
+/// Deflated entry was compressed at level 8 or 9
+const FLAG_DEFLATE_MAXIMUM: u16 = 1 << 1;
+/// Deflated entry was compressed at level 2, or at level 1 together with the maximum flag
+const FLAG_DEFLATE_FAST: u16 = 1 << 2;
+/// Filename and comment are encoded in UTF-8
+const FLAG_UTF8_NAME: u16 = 1 << 11;
+/// Internal file attribute bit telling that the entry is text
+const INTERNAL_ATTRIBUTE_TEXT: u16 = 1;
 
 const FILE_RECORD_SIGNATURE: u32 = 0x04034B50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074B50;
+/// Start of the first volume of a split archive, the same value as the data descriptor signature
+const SPLIT_ARCHIVE_SIGNATURE: u32 = 0x08074B50;
 const DIRECTORY_ENTRY_SIGNATURE: u32 = 0x02014B50;
 const END_OF_CENTRAL_DIR_SIGNATURE: u32 = 0x06054B50;
+const ZIP64_END_OF_CENTRAL_DIR_SIGNATURE: u32 = 0x06064B50;
+const ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIGNATURE: u32 = 0x07064B50;
+
+const ZIP64_VERSION_NEEDED_TO_EXTRACT: u16 = 45;
+#[cfg(feature = "bzip2")]
+const BZIP2_VERSION_NEEDED_TO_EXTRACT: u16 = 46;
+#[cfg(feature = "zstd")]
+const ZSTD_VERSION_NEEDED_TO_EXTRACT: u16 = 63;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+/// Info-ZIP extended timestamp extra field
+const EXTENDED_TIMESTAMP_EXTRA_FIELD_ID: u16 = 0x5455;
+/// Info-ZIP new Unix extra field, with the UID and GID of the owner
+const UNIX_OWNER_EXTRA_FIELD_ID: u16 = 0x7875;
+/// NTFS extra field, with timestamps as Windows FILETIMEs
+const NTFS_EXTRA_FIELD_ID: u16 = 0x000A;
+/// Seconds from 1601-01-01, the start of Windows FILETIMEs, to the Unix epoch
+const FILETIME_UNIX_EPOCH: u64 = 11_644_473_600;
+/// Size of the ZIP64 end of central directory record, not counting the signature and the size
+/// field itself
+const ZIP64_END_OF_CENTRAL_DIR_SIZE: u64 = 44;
+/// Sizes of the records without their variable length fields
+const FILE_RECORD_SIZE: u64 = 30;
+const DIRECTORY_ENTRY_SIZE: u64 = 46;
+const END_OF_CENTRAL_DIR_SIZE: u64 = 22;
+/// Size of the ZIP64 end of central directory record and locator together
+const ZIP64_END_OF_CENTRAL_DIR_TOTAL_SIZE: u64 = 12 + ZIP64_END_OF_CENTRAL_DIR_SIZE + 20;
+
+/// Errors that can occur while writing an archive
+#[derive(Debug)]
+pub enum ZipError {
+    /// Reading input or writing to the destination failed
+    Io(std::io::Error),
+    /// More than one entry has the same name
+    DuplicateName(String),
+    /// Archive or entry comment is longer than 65535 bytes
+    CommentTooLong,
+    /// Entry name could make an extractor write outside of the target directory
+    UnsafePath(String),
+    /// Entry name is longer than 65535 bytes
+    NameTooLong,
+    /// Entry name is empty or only whitespace, after sanitizing it
+    EmptyName,
+    /// Compression level is above 9
+    InvalidCompressionLevel(u32),
+    /// Archive has no entries, see [`ZipArchive::set_reject_empty`]
+    EmptyArchive,
+    /// Entry data doesn't decompress to data with the recorded CRC and size, see
+    /// [`BuiltArchive::verify`]
+    CorruptEntry(String),
+    /// Existing archive couldn't be parsed
+    InvalidArchive(&'static str),
+    /// Entries were added with [`ZipArchive::set_checksums_only`], so there is no data to write
+    ChecksumsOnly,
+    /// No entry of an existing archive has the name, see [`ZipArchive::extract_entry`]
+    EntryNotFound(String),
+    /// Entry of an existing archive uses a compression method that isn't supported, with the
+    /// method's value
+    UnsupportedCompression(u16),
+    /// Building the entry with the name panicked on the thread pool, the panic is reported as this
+    /// error instead of leaving the entry out
+    JobPanicked(String),
+    /// Archive was cancelled with its [`CancelToken`]
+    Cancelled,
+    /// Building the entry with the id failed on the thread pool, with the reason
+    EntryFailed(EntryId, Box<ZipError>),
+    /// Archive can't be split into volumes, see [`ZipArchive::write_split`]
+    CannotSplit(&'static str),
+    /// Built archive can't be merged into another one, see [`ZipArchive::absorb`]
+    CannotAbsorb(&'static str),
+    /// Path given to [`ZipArchive::add_file_from_fs`] is a directory, use
+    /// [`ZipArchive::add_directory_from_fs`] or [`ZipArchive::add_directory_recursive`] instead
+    ExpectedFile(String),
+}
+
+impl std::fmt::Display for ZipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::DuplicateName(name) => write!(f, "duplicate entry name: {name}"),
+            Self::CommentTooLong => f.write_str("comment is longer than 65535 bytes"),
+            Self::UnsafePath(name) => write!(f, "unsafe entry name: {name}"),
+            Self::NameTooLong => f.write_str("entry name is longer than 65535 bytes"),
+            Self::EmptyName => f.write_str("entry name is empty"),
+            Self::InvalidCompressionLevel(level) => {
+                write!(f, "invalid compression level: {level}")
+            }
+            Self::EmptyArchive => f.write_str("archive has no entries"),
+            Self::CorruptEntry(name) => write!(f, "corrupt entry: {name}"),
+            Self::InvalidArchive(message) => write!(f, "invalid archive: {message}"),
+            Self::ChecksumsOnly => f.write_str("entries only have checksums and can't be written"),
+            Self::EntryNotFound(name) => write!(f, "entry not found: {name}"),
+            Self::UnsupportedCompression(method) => {
+                write!(f, "unsupported compression method: {method}")
+            }
+            Self::JobPanicked(name) => write!(f, "building entry {name} panicked"),
+            Self::Cancelled => f.write_str("archive was cancelled"),
+            Self::EntryFailed(id, e) => write!(f, "entry {} failed: {e}", id.0),
+            Self::CannotSplit(reason) => write!(f, "archive can't be split: {reason}"),
+            Self::CannotAbsorb(reason) => write!(f, "archive can't be absorbed: {reason}"),
+            Self::ExpectedFile(path) => write!(f, "expected a file, found a directory: {path}"),
+        }
+    }
+}
+
+impl std::error::Error for ZipError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::EntryFailed(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ZipError {
+    /// Copy of the error, for errors that are returned more than once. I/O errors keep their kind
+    /// and message, but not their source.
+    fn duplicate(&self) -> Self {
+        match self {
+            Self::Io(e) => Self::Io(std::io::Error::new(e.kind(), e.to_string())),
+            Self::DuplicateName(name) => Self::DuplicateName(name.clone()),
+            Self::CommentTooLong => Self::CommentTooLong,
+            Self::UnsafePath(name) => Self::UnsafePath(name.clone()),
+            Self::NameTooLong => Self::NameTooLong,
+            Self::EmptyName => Self::EmptyName,
+            Self::InvalidCompressionLevel(level) => Self::InvalidCompressionLevel(*level),
+            Self::EmptyArchive => Self::EmptyArchive,
+            Self::CorruptEntry(name) => Self::CorruptEntry(name.clone()),
+            Self::InvalidArchive(message) => Self::InvalidArchive(message),
+            Self::ChecksumsOnly => Self::ChecksumsOnly,
+            Self::EntryNotFound(name) => Self::EntryNotFound(name.clone()),
+            Self::UnsupportedCompression(method) => Self::UnsupportedCompression(*method),
+            Self::JobPanicked(name) => Self::JobPanicked(name.clone()),
+            Self::Cancelled => Self::Cancelled,
+            Self::EntryFailed(id, e) => Self::EntryFailed(*id, Box::new(e.duplicate())),
+            Self::CannotSplit(reason) => Self::CannotSplit(reason),
+            Self::CannotAbsorb(reason) => Self::CannotAbsorb(reason),
+            Self::ExpectedFile(path) => Self::ExpectedFile(path.clone()),
+        }
+    }
+}
+
+impl From<std::io::Error> for ZipError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Summary of a written archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ArchiveStats {
+    /// Number of entries, including directories
+    pub entries: usize,
+    /// Number of bytes written to the destination. When appending, this doesn't include the
+    /// entries that were already in the archive.
+    pub bytes_written: u64,
+    /// Total size of the entries' data as stored in the archive
+    pub compressed_bytes: u64,
+    /// Total size of the entries' data before compression
+    pub uncompressed_bytes: u64,
+    /// Offset of the central directory as recorded in the end of central directory record. For
+    /// split archives it's relative to the start of the volume the central directory starts on.
+    pub central_dir_offset: u64,
+    /// Size of the central directory
+    pub central_dir_size: u64,
+    /// Whether the archive is too large or has too many entries for the classic format, so it
+    /// uses ZIP64, which some old extractors don't support
+    pub zip64_used: bool,
+    /// Offset right after the end of central directory record, where the archive ends. For split
+    /// archives it's relative to the start of the last volume. When appending to a destination
+    /// that isn't truncated, anything after it is left over from the old archive.
+    pub end_offset: u64,
+}
+
+/// Identifies an entry added to a [`ZipArchive`], returned by the `add_*` methods. Entries are
+/// numbered in the order they are added, starting at 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EntryId(usize);
+
+impl EntryId {
+    /// Position of the entry in the order entries were added
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Information about an entry that was compressed
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EntryInfo {
+    /// Id returned when the entry was added, `None` for entries of an existing archive
+    pub id: Option<EntryId>,
+    pub name: String,
+    /// Compression method as stored in the archive, see [`CompressionType`] for the values
+    pub compression_method: u16,
+    /// CRC-32 of the uncompressed data
+    pub crc: u32,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+}
+
+impl EntryInfo {
+    /// Compressed size divided by the uncompressed size. Values close to 1 or above mean that
+    /// compressing didn't help and the entry could have been stored. Empty entries have a ratio
+    /// of 1.
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_size == 0 {
+            1.0
+        } else {
+            self.compressed_size as f64 / self.uncompressed_size as f64
+        }
+    }
+}
+
+/// Checksum of an entry, computed by [`ZipArchive::compute_checksums`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EntryChecksum {
+    pub name: String,
+    /// CRC-32 of the contents
+    pub crc: u32,
+    pub size: u64,
+}
+
+/// Entries with the same contents, found by [`BuiltArchive::duplicate_report`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DuplicateContents {
+    /// CRC-32 of the contents
+    pub crc: u32,
+    pub uncompressed_size: u64,
+    /// Names of the entries, in the order they are written
+    pub names: Vec<String>,
+}
+
+/// Progress of [`ZipArchive::write`], reported after each entry is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ProgressEvent<'n> {
+    /// Id returned when the entry was added
+    pub id: EntryId,
+    /// Name of the entry that was just written
+    pub filename: &'n str,
+    /// Number of entries written so far, including this one
+    pub completed: usize,
+}
 
-/// Making archives with stored compression is not supported yet and only used on directory
-/// entries.
+/// Compression method of an entry. Directory entries are always stored.
+///
+/// Deflate64 (method 9) is not supported. The available deflate implementations can only decode
+/// it, and a larger window alone wouldn't be worth maintaining an encoder here.
 #[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    Stored = 0,
+    Deflate = 8,
+    /// Requires the `bzip2` feature. Not every extractor supports bzip2.
+    #[cfg(feature = "bzip2")]
+    Bzip2 = 12,
+    /// Requires the `zstd` feature. Zstandard in zip is a fairly recent addition, so only some
+    /// extractors support it, like 7-Zip 21 and newer and WinZip. The compression level is passed
+    /// to zstd as is, with 0 meaning zstd's default level.
+    #[cfg(feature = "zstd")]
+    Zstd = 93,
+}
+
+impl CompressionType {
+    /// Compression type with the given method value, if it's supported
+    fn from_method(method: u16) -> Option<Self> {
+        match method {
+            0 => Some(Self::Stored),
+            8 => Some(Self::Deflate),
+            #[cfg(feature = "bzip2")]
+            12 => Some(Self::Bzip2),
+            #[cfg(feature = "zstd")]
+            93 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// How entry names are checked for path traversal. Backslashes are always replaced with forward
+/// slashes and repeated slashes are merged into one. Leading slashes are removed, except in
+/// [`NameSanitization::Strict`] mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameSanitization {
+    /// Keep `.` and `..` components as they are
+    #[default]
+    Off,
+    /// Remove `.` and empty components and resolve `..` components, so `a/../b` becomes `b`. `..`
+    /// components that would go above the root are removed. Names starting with a drive letter
+    /// are rejected with [`ZipError::UnsafePath`].
+    Sanitize,
+    /// Reject names with leading slashes, drive letters or `..` components with
+    /// [`ZipError::UnsafePath`]
+    Strict,
+}
+
+/// What [`ZipArchive::add_directory_recursive`] does with symlinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkHandling {
+    /// Leave symlinks out of the archive
+    #[default]
+    Skip,
+    /// Archive whatever the symlink points to. Symlink loops are not detected.
+    Follow,
+    /// Add symlinks as symlink entries, like [`ZipArchive::add_symlink`] does
+    Store,
+}
+
+/// Order in which [`ZipArchive::write`] writes the entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EntryOrder {
+    /// The order in which the entries finished compressing, which depends on thread scheduling
+    #[default]
+    Completion,
+    /// The order in which the entries were added
+    Insertion,
+    /// Sorted by entry name, entries with the same name are in the order they were added
+    Name,
+}
+
+/// System an archive claims to be made on, stored in the high byte of the version made by field.
+/// Extractors use it to decide how to interpret the external file attributes, with the Unix mode
+/// in the upper 16 bits only being read on Unix-like systems.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostSystem {
+    /// MS-DOS and Windows with FAT filesystems, external attributes are MS-DOS attributes
+    MsDos = 0,
+    #[default]
+    Unix = 3,
+    /// Windows with NTFS
+    WindowsNtfs = 10,
+    /// macOS
+    Osx = 19,
+}
+
+/// How the external file attributes of new entries are written, see
+/// [`ZipArchive::set_external_attributes_mode`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttributeMode {
+    /// Unix mode in the upper 16 bits, with MS-DOS attributes in the lower 16 bits if
+    /// [`ZipArchive::set_dos_attributes`] is on
+    #[default]
+    Unix,
+    /// Only MS-DOS attributes, derived from the Unix mode, with the upper 16 bits left empty
+    Dos,
+    /// No attributes at all, extractors fall back to their defaults
+    Zero,
+}
+
+/// How entries are encrypted, see [`ZipArchive::set_password`]. Each method requires the feature
+/// of the same name, the enum has no variants without them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionMethod {
+    /// AES-256 as specified by WinZip's AE-2 format. Extracting these entries requires an
+    /// extractor that supports them, like 7-Zip or WinZip.
+    #[cfg(feature = "aes")]
+    Aes256,
+    /// Traditional PKWARE encryption. It's broken and only keeps out casual readers, but nearly
+    /// every extractor supports it, including Info-ZIP's `unzip -P`. Entries encrypted with it
+    /// never get data descriptors, since extractors would check the password against a different
+    /// value then.
+    #[cfg(feature = "zipcrypto")]
+    ZipCrypto,
+}
+
+#[cfg(any(feature = "aes", feature = "zipcrypto"))]
+impl Default for EncryptionMethod {
+    /// AES-256 if the `aes` feature is enabled, ZipCrypto otherwise
+    fn default() -> Self {
+        #[cfg(feature = "aes")]
+        return Self::Aes256;
+        #[cfg(not(feature = "aes"))]
+        return Self::ZipCrypto;
+    }
+}
+
+/// Password used to encrypt entries. It's kept out of `Debug` output.
+#[cfg(any(feature = "aes", feature = "zipcrypto"))]
+#[derive(Clone)]
+struct Password(String);
+
+#[cfg(any(feature = "aes", feature = "zipcrypto"))]
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Password")
+    }
+}
+
+/// Settings of a single entry, used with the `_with` variants of the methods that add files
+#[derive(Debug, Clone, Default)]
+pub struct FileOptions {
+    comment: String,
+    compression_type: Option<CompressionType>,
+    text: Option<bool>,
+    external_attributes: Option<u32>,
+    modified: Option<SystemTime>,
+}
+
+impl FileOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the comment of the entry. The comment can be at most 65535 bytes long, otherwise
+    /// adding the entry fails with [`ZipError::CommentTooLong`].
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = comment.to_string();
+        self
+    }
+
+    /// Check the options before an entry is added with them
+    fn check(&self) -> Result<(), ZipError> {
+        if self.comment.len() > u16::MAX as usize {
+            return Err(ZipError::CommentTooLong);
+        }
+        Ok(())
+    }
+
+    /// Set the compression method of the entry, instead of the one set on the archive
+    pub fn compression_type(mut self, compression_type: CompressionType) -> Self {
+        self.compression_type = Some(compression_type);
+        self
+    }
+
+    /// Mark the entry as text or binary, instead of guessing with
+    /// [`ZipArchive::set_detect_text`]. Some extractors convert line endings of text entries.
+    pub fn text(mut self, text: bool) -> Self {
+        self.text = Some(text);
+        self
+    }
+
+    /// Set the external file attributes of the entry instead of taking them from the source file.
+    /// The upper 16 bits are the Unix mode, including the file type, and the lower 16 bits are
+    /// MS-DOS attributes, like `0o100755 << 16` for an executable or `0x01` for read-only.
+    pub fn external_attributes(mut self, external_attributes: u32) -> Self {
+        self.external_attributes = Some(external_attributes);
+        self
+    }
+
+    /// Set the modification time of the entry instead of using the current time or the time of
+    /// the source file. It's used even when the archive has a fixed timestamp or is reproducible,
+    /// but still clamped by [`ZipArchive::set_timestamp_clamp`].
+    pub fn modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+}
+
+/// Archive-wide settings needed on the thread pool to build an entry
+#[derive(Debug, Clone)]
+struct EntrySettings {
+    compression_type: CompressionType,
+    compression_level: Compression,
+    #[cfg(feature = "tempfile")]
+    use_temp_files: bool,
+    auto_store: bool,
+    extended_timestamps: bool,
+    ntfs_timestamps: bool,
+    preserve_ownership: bool,
+    detect_text: bool,
+    /// Size of the chunks deflated in parallel, if entries are split into chunks
+    deflate_chunk_size: Option<usize>,
+    #[cfg(feature = "memmap2")]
+    memory_map: bool,
+    #[cfg(feature = "deflate-dictionary")]
+    deflate_dictionary: Option<Arc<[u8]>>,
+    /// Timestamp stored for every entry instead of its real times
+    fixed_timestamp: Option<SystemTime>,
+    /// Latest timestamp stored, later times are replaced with it
+    timestamp_clamp: Option<SystemTime>,
+    reproducible: bool,
+    checksums_only: bool,
+    /// Makes reading files fail once the archive is cancelled
+    cancel_token: CancelToken,
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    password: Option<Password>,
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    encryption_method: EncryptionMethod,
+}
+
+impl EntrySettings {
+    /// Same settings, but without compression
+    fn stored(&self) -> Self {
+        Self {
+            compression_type: CompressionType::Stored,
+            ..self.clone()
+        }
+    }
+
+    /// Timestamp stored for every entry instead of its real times, if there is one
+    fn fixed_time(&self) -> Option<SystemTime> {
+        self.fixed_timestamp.or_else(|| {
+            self.reproducible
+                .then(|| UNIX_EPOCH + Duration::from_secs(REPRODUCIBLE_TIMESTAMP))
+        })
+    }
+
+    /// `time`, or the clamp timestamp if that is earlier
+    fn clamp_time(&self, time: SystemTime) -> SystemTime {
+        match self.timestamp_clamp {
+            Some(clamp) => time.min(clamp),
+            None => time,
+        }
+    }
+
+    /// Packed modification time and, if enabled, the extended timestamp of a file or directory
+    /// from the filesystem
+    fn fs_timestamps(&self, metadata: &Metadata) -> ((u16, u16), Option<ExtendedTimestamp>) {
+        let (modified, accessed, created) = match self.fixed_time() {
+            Some(fixed_time) => (self.clamp_time(fixed_time), None, None),
+            None => (
+                self.clamp_time(metadata.modified().unwrap_or_else(|_| SystemTime::now())),
+                metadata.accessed().ok().map(|time| self.clamp_time(time)),
+                metadata.created().ok().map(|time| self.clamp_time(time)),
+            ),
+        };
+        let extended_timestamp = self.extended_timestamps.then(|| ExtendedTimestamp {
+            modified: Some(unix_timestamp(modified)),
+            accessed: accessed.map(unix_timestamp),
+            created: created.map(unix_timestamp),
+        });
+        (dos_date_time(modified), extended_timestamp)
+    }
+
+    /// NTFS timestamps of a file or directory from the filesystem, if they are enabled. The field
+    /// always has all three times, the modification time stands in for the ones that aren't
+    /// available.
+    fn fs_ntfs_timestamp(&self, metadata: &Metadata) -> Option<NtfsTimestamp> {
+        if !self.ntfs_timestamps {
+            return None;
+        }
+        let (modified, accessed, created) = match self.fixed_time() {
+            Some(fixed_time) => (fixed_time, None, None),
+            None => (
+                metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+                metadata.accessed().ok(),
+                metadata.created().ok(),
+            ),
+        };
+        let modified = self.clamp_time(modified);
+        let or_modified = |time: Option<SystemTime>| {
+            filetime(time.map_or(modified, |time| self.clamp_time(time)))
+        };
+        Some(NtfsTimestamp {
+            modified: filetime(modified),
+            accessed: or_modified(accessed),
+            created: or_modified(created),
+        })
+    }
+
+    /// Owner of a file or directory from the filesystem, if ownership is preserved
+    fn fs_owner(&self, metadata: &Metadata) -> Option<UnixOwner> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            self.preserve_ownership.then(|| UnixOwner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = metadata;
+            None
+        }
+    }
+
+    /// Modification time stored for an entry that isn't from the filesystem
+    fn now(&self) -> SystemTime {
+        self.clamp_time(self.fixed_time().unwrap_or_else(SystemTime::now))
+    }
+
+    /// Packed modification time and, if enabled, the extended timestamp of an entry that isn't
+    /// from the filesystem, at `modified` if it's given
+    fn timestamps(&self, modified: Option<SystemTime>) -> ((u16, u16), Option<ExtendedTimestamp>) {
+        let modified = match modified {
+            Some(modified) => self.clamp_time(modified),
+            None => self.now(),
+        };
+        let extended_timestamp = self
+            .extended_timestamps
+            .then(|| ExtendedTimestamp::modified(modified));
+        (dos_date_time(modified), extended_timestamp)
+    }
+
+    /// Encrypt compressed data if a password is set, returning the method it was encrypted with.
+    /// `crc` is the CRC of the uncompressed data.
+    #[cfg_attr(not(feature = "zipcrypto"), allow(unused_variables))]
+    fn encrypt(
+        &self,
+        data: ZipFileData,
+        crc: u32,
+    ) -> Result<(ZipFileData, Option<EncryptionMethod>), std::io::Error> {
+        #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+        if let Some(password) = self.password.as_ref().filter(|_| !self.checksums_only) {
+            let data = match self.encryption_method {
+                #[cfg(feature = "aes")]
+                EncryptionMethod::Aes256 => data.transform(aes::OVERHEAD, |reader, output| {
+                    aes::encrypt(reader, output, password)
+                })?,
+                #[cfg(feature = "zipcrypto")]
+                EncryptionMethod::ZipCrypto => data
+                    .transform(zipcrypto::HEADER_LEN, |reader, output| {
+                        zipcrypto::encrypt(reader, output, password, crc)
+                    })?,
+            };
+            return Ok((data, Some(self.encryption_method)));
+        }
+        Ok((data, None))
+    }
+
+    /// Whether data compressed with `compression_type` was deflated with a preset dictionary
+    fn has_dictionary(&self, compression_type: CompressionType) -> bool {
+        #[cfg(feature = "deflate-dictionary")]
+        if compression_type == CompressionType::Deflate && self.deflate_dictionary.is_some() {
+            return true;
+        }
+        let _ = compression_type;
+        false
+    }
+
+    /// Whether compressing didn't make the data any smaller and it should be stored as is instead
+    fn should_store_instead(&self, compressed_size: u64, uncompressed_size: u64) -> bool {
+        self.auto_store
+            && self.compression_type != CompressionType::Stored
+            && compressed_size >= uncompressed_size
+    }
+}
+
+#[derive(Debug)]
+pub struct ZipArchive<'a> {
+    /// Pool that compresses the files. Rayon's global pool is used if there is none.
+    thread_pool: Option<&'a ThreadPool>,
+    /// Whether files are compressed right away on the calling thread instead, see
+    /// [`ZipArchive::new_sequential`]
+    sequential: bool,
+    tx: EntrySender,
+    rx: Receiver<Result<ZipFile, ZipError>>,
+    entry_settings: EntrySettings,
+    symlink_handling: SymlinkHandling,
+    allow_duplicates: bool,
+    reject_empty: bool,
+    name_sanitization: NameSanitization,
+    entry_order: EntryOrder,
+    /// Host system in the high byte and specification version in the low byte
+    version_made_by: u16,
+    dos_attributes: bool,
+    attribute_mode: AttributeMode,
+    comment: Vec<u8>,
+    /// Entries of the archive that is being appended to, their data is never rewritten
+    existing_entries: Vec<CentralDirectoryEntry>,
+    /// Offset at which new entries are written, the old central directory offset when appending
+    start_offset: u64,
+    progress: Option<ProgressCallback<'a>>,
+    name_mapper: Option<NameMapper<'a>>,
+    entry_filter: Option<EntryFilter<'a>>,
+    /// Number of entries added so far
+    queued: usize,
+    /// Entries already received from the channel by [`ZipArchive::collect_entries`]
+    collected: Vec<ZipFile>,
+    /// Number of entries received from the channel, including failed ones
+    received: usize,
+    /// Error of the first entry that failed in [`ZipArchive::collect_entries`]. The archive can't
+    /// be written anymore once an entry is missing from it.
+    failed: Option<ZipError>,
+    /// Whether entries were added in checksums only mode, which makes the archive unwritable
+    has_checksums_only: bool,
+    /// Stops jobs that haven't started yet once the archive is gone
+    cancelled: CancelOnDrop,
+    /// Bounds how many jobs added from now on run at once
+    job_limit: Option<JobLimit>,
+}
+
+/// Handle that cancels an archive from another thread, returned by [`ZipArchive::cancel_token`]
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Stop compressing the files of the archive. Files that are being compressed stop at their
+    /// next read, and [`ZipArchive::write`] and [`ZipArchive::build`] return
+    /// [`ZipError::Cancelled`].
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Flag that is set when the archive is dropped, whether it was written or not
+#[derive(Debug, Default)]
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Limit set with [`ZipArchive::set_max_compression_threads`]. Jobs over the limit wait in a queue
+/// instead of blocking threads of the pool, and each job that finishes runs the next queued one on
+/// its thread.
+#[derive(Clone)]
+struct JobLimit(Arc<Mutex<JobQueue>>);
+
+struct JobQueue {
+    max_running: usize,
+    running: usize,
+    waiting: VecDeque<Job>,
+}
+
+impl std::fmt::Debug for JobLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("JobLimit")
+    }
+}
+
+impl JobLimit {
+    fn new(max_running: usize) -> Self {
+        Self(Arc::new(Mutex::new(JobQueue {
+            max_running: max_running.max(1),
+            running: 0,
+            waiting: VecDeque::new(),
+        })))
+    }
+
+    /// Return `job` if it can start right away, otherwise queue it
+    fn admit(&self, job: Job) -> Option<Job> {
+        let mut queue = self.0.lock().unwrap();
+        if queue.running < queue.max_running {
+            queue.running += 1;
+            Some(job)
+        } else {
+            queue.waiting.push_back(job);
+            None
+        }
+    }
+
+    /// Run `job` and then the queued jobs until there are none left
+    fn run(&self, mut job: Job) {
+        loop {
+            job();
+            let mut queue = self.0.lock().unwrap();
+            match queue.waiting.pop_front() {
+                Some(next) => job = next,
+                None => {
+                    queue.running -= 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Callback set with [`ZipArchive::on_progress`]
+struct ProgressCallback<'a>(Box<dyn Fn(ProgressEvent) + Send + Sync + 'a>);
+
+impl std::fmt::Debug for ProgressCallback<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Function set with [`ZipArchive::set_name_mapper`]
+struct NameMapper<'a>(Box<dyn Fn(&str) -> String + Send + Sync + 'a>);
+
+impl std::fmt::Debug for NameMapper<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NameMapper")
+    }
+}
+
+type EntryFilterFn<'a> = dyn Fn(&Path, &Metadata) -> bool + Send + Sync + 'a;
+
+/// Function set with [`ZipArchive::set_entry_filter`]
+struct EntryFilter<'a>(Box<EntryFilterFn<'a>>);
+
+impl std::fmt::Debug for EntryFilter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EntryFilter")
+    }
+}
+
+impl<'a> ZipArchive<'a> {
+    /// Start configuring an archive, see [`ZipArchiveBuilder`]
+    pub fn builder() -> ZipArchiveBuilder<'a> {
+        ZipArchiveBuilder::new()
+    }
+
+    pub fn new(thread_pool: &'a ThreadPool) -> Self {
+        Self::with_compression_level(thread_pool, DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Create an archive that deflates files with the given compression level. Levels above 9 are
+    /// clamped to 9. Level 0 is the fastest and doesn't compress the data at all.
+    pub fn with_compression_level(thread_pool: &'a ThreadPool, level: u32) -> Self {
+        Self::with_pool_and_level(Some(thread_pool), level)
+    }
+
+    /// Create an archive that buffers at most `max_in_flight` compressed files that
+    /// [`ZipArchive::write`] hasn't received yet. Once that many are buffered, compressing more
+    /// files waits until `write` takes some, which bounds the memory used by the channel. The
+    /// waiting jobs occupy threads of `thread_pool` until then.
+    pub fn with_capacity(thread_pool: &'a ThreadPool, max_in_flight: usize) -> Self {
+        let (tx, rx) = sync_channel(max_in_flight);
+        Self::with_channel(
+            Some(thread_pool),
+            DEFAULT_COMPRESSION_LEVEL,
+            EntrySender::Bounded(tx),
+            rx,
+        )
+    }
+
+    fn with_pool_and_level(thread_pool: Option<&'a ThreadPool>, level: u32) -> Self {
+        let (tx, rx) = channel();
+        Self::with_channel(thread_pool, level, EntrySender::Unbounded(tx), rx)
+    }
+
+    fn with_channel(
+        thread_pool: Option<&'a ThreadPool>,
+        level: u32,
+        tx: EntrySender,
+        rx: Receiver<Result<ZipFile, ZipError>>,
+    ) -> Self {
+        Self {
+            thread_pool,
+            sequential: false,
+            tx,
+            rx,
+            entry_settings: EntrySettings {
+                compression_type: CompressionType::Deflate,
+                compression_level: Compression::new(level.min(MAX_COMPRESSION_LEVEL)),
+                #[cfg(feature = "tempfile")]
+                use_temp_files: false,
+                auto_store: false,
+                extended_timestamps: false,
+                ntfs_timestamps: false,
+                preserve_ownership: false,
+                detect_text: false,
+                deflate_chunk_size: None,
+                #[cfg(feature = "memmap2")]
+                memory_map: false,
+                #[cfg(feature = "deflate-dictionary")]
+                deflate_dictionary: None,
+                fixed_timestamp: None,
+                timestamp_clamp: None,
+                reproducible: false,
+                checksums_only: false,
+                cancel_token: CancelToken::default(),
+                #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+                password: None,
+                #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+                encryption_method: EncryptionMethod::default(),
+            },
+            symlink_handling: SymlinkHandling::Skip,
+            allow_duplicates: false,
+            reject_empty: false,
+            name_sanitization: NameSanitization::Off,
+            entry_order: EntryOrder::Completion,
+            version_made_by: version_made_by(HostSystem::Unix, SPEC_VERSION),
+            dos_attributes: false,
+            attribute_mode: AttributeMode::Unix,
+            comment: Vec::new(),
+            existing_entries: Vec::new(),
+            start_offset: 0,
+            progress: None,
+            name_mapper: None,
+            entry_filter: None,
+            queued: 0,
+            collected: Vec::new(),
+            received: 0,
+            failed: None,
+            has_checksums_only: false,
+            cancelled: CancelOnDrop::default(),
+            job_limit: None,
+        }
+    }
+
+    /// Open an existing archive to add more files to it. The central directory of `archive` is
+    /// read and `archive` is left positioned at its start, so passing the same `archive` to
+    /// [`ZipArchive::write`] overwrites the old central directory with the new entries, followed
+    /// by a central directory listing both the old and the new ones.
+    ///
+    /// The new archive can be shorter than the old one, for example with a shorter comment or
+    /// without the ZIP64 records the old one needed, and the leftover bytes at the end would make
+    /// it unreadable. [`ZipArchive::write_to_file`] truncates a file to the new length, other
+    /// destinations have to be truncated at [`ArchiveStats::end_offset`] by the caller.
+    ///
+    /// The archive comment is kept, entries that were already in the archive are not modified.
+    pub fn append<R: Read + Seek>(
+        thread_pool: &'a ThreadPool,
+        archive: &mut R,
+    ) -> Result<Self, ZipError> {
+        Self::append_with_pool(Some(thread_pool), archive)
+    }
+
+    /// Read the central directory of an existing archive and return information about its
+    /// entries, in the order they are listed. No entry data is read or decompressed. Names that
+    /// aren't valid UTF-8 have the invalid parts replaced.
+    pub fn list_entries<R: Read + Seek>(archive: &mut R) -> Result<Vec<EntryInfo>, ZipError> {
+        let central_directory = CentralDirectory::read(archive)?;
+        Ok(central_directory
+            .entries
+            .iter()
+            .map(CentralDirectoryEntry::info)
+            .collect())
+    }
+
+    /// Read the entry named `name` from an existing archive and decompress it. The CRC and size of
+    /// the data are checked, a mismatch is reported as [`ZipError::CorruptEntry`]. If several
+    /// entries have the name, the last one is extracted. Encrypted entries are not supported.
+    pub fn extract_entry<R: Read + Seek>(archive: &mut R, name: &str) -> Result<Vec<u8>, ZipError> {
+        let central_directory = CentralDirectory::read(archive)?;
+        let entry = central_directory
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| entry.filename == name)
+            .ok_or_else(|| ZipError::EntryNotFound(name.to_string()))?;
+        entry.extract(archive)
+    }
+
+    /// Read the entry named `name` from an existing archive as a stream, decompressing it while
+    /// it's read instead of all at once like [`ZipArchive::extract_entry`] does. The CRC and size
+    /// are checked once the end of the data is reached, a mismatch is reported as an
+    /// [`ErrorKind::InvalidData`] error from the last read.
+    pub fn entry_reader<'r, R: Read + Seek>(
+        archive: &'r mut R,
+        name: &str,
+    ) -> Result<impl Read + 'r, ZipError> {
+        let central_directory = CentralDirectory::read(archive)?;
+        let entry = central_directory
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| entry.filename == name)
+            .ok_or_else(|| ZipError::EntryNotFound(name.to_string()))?;
+        entry.reader(archive)
+    }
+
+    fn append_with_pool<R: Read + Seek>(
+        thread_pool: Option<&'a ThreadPool>,
+        archive: &mut R,
+    ) -> Result<Self, ZipError> {
+        let central_directory = CentralDirectory::read(archive)?;
+        archive.seek(SeekFrom::Start(central_directory.offset))?;
+        let mut zip_archive = Self::with_pool_and_level(thread_pool, DEFAULT_COMPRESSION_LEVEL);
+        zip_archive.comment = central_directory.comment;
+        zip_archive.existing_entries = central_directory.entries;
+        zip_archive.start_offset = central_directory.offset;
+        Ok(zip_archive)
+    }
+
+    /// Set whether multiple entries may have the same name. By default [`ZipArchive::write`]
+    /// returns [`ZipError::DuplicateName`] if it finds any.
+    pub fn set_allow_duplicates(&mut self, allow_duplicates: bool) {
+        self.allow_duplicates = allow_duplicates;
+    }
+
+    /// Set whether [`ZipArchive::write`] returns [`ZipError::EmptyArchive`] instead of writing an
+    /// archive without entries. Empty archives are valid, so by default they are written.
+    pub fn set_reject_empty(&mut self, reject_empty: bool) {
+        self.reject_empty = reject_empty;
+    }
+
+    /// Set how names of entries added after this call are checked for components that could make
+    /// an extractor write outside of the target directory
+    pub fn set_name_sanitization(&mut self, name_sanitization: NameSanitization) {
+        self.name_sanitization = name_sanitization;
+    }
+
+    /// Set the order in which entries are written. By default they are written as soon as they
+    /// are compressed, so the order can change between runs. Entries that were already in an
+    /// archive that is appended to always stay in front of the new ones.
+    pub fn set_entry_order(&mut self, entry_order: EntryOrder) {
+        self.entry_order = entry_order;
+    }
+
+    /// Set the host system and the specification version, like 63 for 6.3, written to the central
+    /// directory entries of new entries. By default archives claim to be made on Unix by version
+    /// 6.3. With [`HostSystem::MsDos`], extractors ignore the Unix permissions of the entries.
+    pub fn set_version_made_by(&mut self, host_system: HostSystem, spec_version: u8) {
+        self.version_made_by = version_made_by(host_system, spec_version);
+    }
+
+    /// Set whether MS-DOS attributes are stored next to the Unix permissions of entries, for
+    /// extractors that only look at them, usually with [`HostSystem::MsDos`]. Directories get the
+    /// directory attribute, other entries the archive attribute, and entries whose owner can't
+    /// write them the read-only attribute. Entries that already have MS-DOS attributes from
+    /// [`FileOptions::external_attributes`] and entries of an archive that is appended to are
+    /// left as they are.
+    pub fn set_dos_attributes(&mut self, dos_attributes: bool) {
+        self.dos_attributes = dos_attributes;
+    }
+
+    /// Set how the external file attributes of new entries are written, to find out how an
+    /// extractor handles them or to work around one that misinterprets Unix modes. Applies to
+    /// attributes given with [`FileOptions::external_attributes`] too, but not to entries of an
+    /// archive that is appended to.
+    pub fn set_external_attributes_mode(&mut self, attribute_mode: AttributeMode) {
+        self.attribute_mode = attribute_mode;
+    }
+
+    /// Set a function that rewrites the names of entries added after this call, like stripping a
+    /// prefix or adding a top level directory. It's called with the name given to the add method,
+    /// or the full name in the archive for [`ZipArchive::add_directory_recursive`], and its result
+    /// goes through name sanitization like any other name.
+    pub fn set_name_mapper<F: Fn(&str) -> String + Send + Sync + 'a>(&mut self, f: F) {
+        self.name_mapper = Some(NameMapper(Box::new(f)));
+    }
+
+    /// Set a function that [`ZipArchive::add_directory_recursive`] calls for every path it finds,
+    /// like a `.git` directory or files over some size, with the path on the filesystem and its
+    /// metadata. Paths it returns `false` for are skipped, directories with everything in them.
+    /// Symlinks get the metadata of the link when they are stored and of the target when they are
+    /// followed.
+    pub fn set_entry_filter<F: Fn(&Path, &Metadata) -> bool + Send + Sync + 'a>(&mut self, f: F) {
+        self.entry_filter = Some(EntryFilter(Box::new(f)));
+    }
+
+    /// Turn a name given to an add method into the name of the entry
+    fn entry_name(&self, archived_name: &str) -> Result<String, ZipError> {
+        match &self.name_mapper {
+            Some(NameMapper(mapper)) => {
+                normalize_name(&mapper(archived_name), self.name_sanitization)
+            }
+            None => normalize_name(archived_name, self.name_sanitization),
+        }
+    }
+
+    /// Set the comment of the whole archive. The comment can be at most 65535 bytes long, longer
+    /// comments are rejected and leave the previous comment in place.
+    pub fn set_comment(&mut self, comment: &str) -> Result<(), ZipError> {
+        if comment.len() > u16::MAX as usize {
+            return Err(ZipError::CommentTooLong);
+        }
+        self.comment = comment.as_bytes().to_vec();
+        Ok(())
+    }
+
+    /// Set the comment of the whole archive to the contents of the file at `fs_path`, like build
+    /// metadata generated by another tool. The contents are stored as they are, so they should be
+    /// UTF-8 or plain ASCII. Like [`ZipArchive::set_comment`], files longer than 65535 bytes are
+    /// rejected with [`ZipError::CommentTooLong`].
+    pub fn set_comment_from_file(&mut self, fs_path: &Path) -> Result<(), ZipError> {
+        let mut comment = Vec::new();
+        File::open(fs_path)?
+            .take(u16::MAX as u64 + 1)
+            .read_to_end(&mut comment)?;
+        if comment.len() > u16::MAX as usize {
+            return Err(ZipError::CommentTooLong);
+        }
+        self.comment = comment;
+        Ok(())
+    }
+
+    /// Settings for building an entry with the given options
+    fn entry_settings_for(&self, options: &FileOptions) -> EntrySettings {
+        let mut settings = self.entry_settings.clone();
+        if let Some(compression_type) = options.compression_type {
+            settings.compression_type = compression_type;
+        }
+        settings
+    }
+
+    /// Run `job` on the archive's thread pool, unless the archive is dropped before it starts. In
+    /// sequential mode it's run right away instead.
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        if self.sequential {
+            return job();
+        }
+        let cancelled = self.cancelled.0.clone();
+        let job = move || {
+            if !cancelled.load(Ordering::Relaxed) {
+                job()
+            }
+        };
+        if let Some(job_limit) = &self.job_limit {
+            let Some(job) = job_limit.admit(Box::new(job)) else {
+                return;
+            };
+            let job_limit = job_limit.clone();
+            return match self.thread_pool {
+                Some(thread_pool) => thread_pool.spawn(move || job_limit.run(job)),
+                None => rayon::spawn(move || job_limit.run(job)),
+            };
+        }
+        match self.thread_pool {
+            Some(thread_pool) => thread_pool.spawn(job),
+            None => rayon::spawn(job),
+        }
+    }
+
+    /// Build an entry with `job` on the thread pool and send it to the archive. `job` gets the name
+    /// of the entry. A panic in it is sent as [`ZipError::JobPanicked`], otherwise the archive
+    /// would wait for an entry that never comes.
+    fn spawn_entry<F>(&mut self, archived_name: String, job: F) -> EntryId
+    where
+        F: FnOnce(&str) -> Result<ZipFile, ZipError> + Send + 'static,
+    {
+        let tx = self.tx.clone();
+        let sequence = self.next_sequence();
+        let cancel_token = self.entry_settings.cancel_token.clone();
+        self.spawn(move || {
+            let entry = if cancel_token.is_cancelled() {
+                Err(ZipError::Cancelled)
+            } else {
+                match catch_unwind(AssertUnwindSafe(|| job(&archived_name))) {
+                    Ok(Ok(file)) => Ok(ZipFile { sequence, ..file }),
+                    // Reading fails with an I/O error once the archive is cancelled
+                    Ok(Err(_)) if cancel_token.is_cancelled() => Err(ZipError::Cancelled),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(ZipError::JobPanicked(archived_name)),
+                }
+            };
+            tx.send(entry.map_err(|e| match e {
+                ZipError::Cancelled => e,
+                e => ZipError::EntryFailed(EntryId(sequence), Box::new(e)),
+            }));
+        });
+        EntryId(sequence)
+    }
+
+    /// Get a token that cancels the archive, for example when the user no longer wants it. Files
+    /// that haven't been compressed yet are skipped, files that are being compressed stop early,
+    /// and writing the archive fails with [`ZipError::Cancelled`].
+    pub fn cancel_token(&self) -> CancelToken {
+        self.entry_settings.cancel_token.clone()
+    }
+
+    /// Number of entries added so far, whether or not they have been compressed yet. Entries that
+    /// were already in an archive that is appended to are not counted.
+    pub fn queued_count(&self) -> usize {
+        self.queued
+    }
+
+    /// Wait for every entry added so far to be compressed and return information about all of
+    /// them, including the ones returned by earlier calls. More entries can be added afterwards.
+    /// Entries that were already in an archive that is appended to are not included.
+    ///
+    /// If compressing some of the entries failed, the first error is returned after waiting for
+    /// the rest. It's returned again by every later call, and by [`ZipArchive::build`] and the
+    /// methods that write the archive, since the archive would be missing the failed entries.
+    pub fn collect_entries(&mut self) -> Result<Vec<EntryInfo>, ZipError> {
+        while self.received < self.queued {
+            // The archive holds a sender, so the channel can't be disconnected
+            let entry = self.rx.recv().unwrap();
+            self.received += 1;
+            match entry {
+                Ok(file) => self.collected.push(file),
+                Err(e) => {
+                    self.failed.get_or_insert(e);
+                }
+            }
+        }
+        match &self.failed {
+            Some(e) => Err(e.duplicate()),
+            None => Ok(self.collected.iter().map(ZipFile::info).collect()),
+        }
+    }
+
+    /// Set whether files added after this call are only read to compute their CRC and size,
+    /// without compressing them or keeping any data. The results are returned by
+    /// [`ZipArchive::compute_checksums`], which is much cheaper than compressing when only a
+    /// manifest is needed. Once such files are added, the archive can't be written anymore and
+    /// [`ZipArchive::build`] returns [`ZipError::ChecksumsOnly`].
+    pub fn set_checksums_only(&mut self, checksums_only: bool) {
+        self.entry_settings.checksums_only = checksums_only;
+    }
+
+    /// Wait for every entry to be processed and return their CRCs and sizes, in the order they
+    /// would be written. Entries that were already in an archive that is appended to are not
+    /// included. If processing some of the entries failed, the first error is returned.
+    pub fn compute_checksums(mut self) -> Result<Vec<EntryChecksum>, ZipError> {
+        self.collect_entries()?;
+        let mut files = std::mem::take(&mut self.collected);
+        sort_entries(
+            &mut files,
+            self.entry_order,
+            self.entry_settings.reproducible,
+        );
+        Ok(files
+            .into_iter()
+            .map(|file| EntryChecksum {
+                name: file.filename,
+                crc: file.crc,
+                size: file.uncompressed_size,
+            })
+            .collect())
+    }
+
+    /// Move the entries of `other` into this archive without compressing them again, like parts
+    /// of an archive that were built separately. They get new ids in the order they had in
+    /// `other`, which are returned. Their names are checked for duplicates when this archive is
+    /// built, like any other names. `other` can't be an archive that is appended to, since the
+    /// data of its old entries isn't in memory.
+    pub fn absorb(&mut self, other: BuiltArchive) -> Result<Vec<EntryId>, ZipError> {
+        if !other.existing_entries.is_empty() || other.start_offset != 0 {
+            return Err(ZipError::CannotAbsorb(
+                "archives that are appended to can't be absorbed",
+            ));
+        }
+        let mut ids = Vec::with_capacity(other.files.len());
+        for mut file in other.files {
+            // Skips the channel, so it's received right away
+            file.sequence = self.queued;
+            self.queued += 1;
+            self.received += 1;
+            ids.push(EntryId(file.sequence));
+            self.collected.push(file);
+        }
+        Ok(ids)
+    }
+
+    /// Set a function that [`ZipArchive::write`] calls after writing each entry. Entries that were
+    /// already in an archive that is appended to are not reported.
+    pub fn on_progress<F: Fn(ProgressEvent) + Send + Sync + 'a>(&mut self, f: F) {
+        self.progress = Some(ProgressCallback(Box::new(f)));
+    }
+
+    /// Queue an entry that was built on the calling thread. Nothing drains a bounded channel
+    /// before [`ZipArchive::write`] is called, so a full one would block forever. In that case the
+    /// entry is sent from the thread pool instead.
+    fn send_entry(&mut self, mut entry: ZipFile) -> EntryId {
+        let sequence = self.next_sequence();
+        entry.sequence = sequence;
+        match &self.tx {
+            EntrySender::Unbounded(tx) => tx.send(Ok(entry)).unwrap(),
+            EntrySender::Bounded(tx) => {
+                let tx = tx.clone();
+                self.spawn(move || {
+                    let _ = tx.send(Ok(entry));
+                });
+            }
+        }
+        EntryId(sequence)
+    }
+
+    /// Sequence number of the next entry that is added
+    fn next_sequence(&mut self) -> usize {
+        self.has_checksums_only |= self.entry_settings.checksums_only;
+        let sequence = self.queued;
+        self.queued += 1;
+        sequence
+    }
+
+    /// Set the compression method used for files added after this call. [`CompressionType::Stored`]
+    /// is useful for data that is already compressed, like images or videos.
+    pub fn set_compression_type(&mut self, compression_type: CompressionType) {
+        self.entry_settings.compression_type = compression_type;
+    }
+
+    /// Set the compression level used for files added after this call. Levels above 9 are clamped
+    /// to 9.
+    pub fn set_compression_level(&mut self, level: u32) {
+        self.entry_settings.compression_level = Compression::new(level.min(MAX_COMPRESSION_LEVEL));
+    }
+
+    /// Set whether deflated files added after this call are split into chunks of `chunk_size`
+    /// bytes that are compressed in parallel, so that a single large file can use the whole thread
+    /// pool. Chunks are compressed independently of each other, which compresses slightly worse,
+    /// and a few chunks per thread are kept in memory at a time. The chunk size is at least 64 KiB.
+    pub fn set_parallel_deflate(&mut self, chunk_size: Option<usize>) {
+        self.entry_settings.deflate_chunk_size =
+            chunk_size.map(|chunk_size| chunk_size.max(MIN_DEFLATE_CHUNK_SIZE));
+    }
+
+    /// Set how many files added after this call are compressed at once, so that the archive
+    /// doesn't take every thread of a shared pool. Files over the limit wait in a queue without
+    /// occupying a thread. `None` removes the limit. The chunks of
+    /// [`ZipArchive::set_parallel_deflate`] are not limited.
+    pub fn set_max_compression_threads(&mut self, max_threads: Option<usize>) {
+        self.job_limit = max_threads.map(JobLimit::new);
+    }
+
+    /// Set a preset dictionary for deflating files added after this call, which compresses many
+    /// small files that share content much better. `None` goes back to deflating without one.
+    /// Requires the `deflate-dictionary` feature, which switches flate2 to its `zlib-rs` backend
+    /// for the whole build, since the default backend can't set dictionaries.
+    ///
+    /// The zip format has no way to store the dictionary, so regular extractors report these
+    /// entries as corrupt. Only use it for archives that are read by something that knows the
+    /// dictionary. Files are not split into chunks for [`ZipArchive::set_parallel_deflate`]
+    /// while a dictionary is set, and [`BuiltArchive::verify`] and
+    /// [`BuiltArchive::verify_roundtrip`] can't decompress these entries, so they skip them.
+    #[cfg(feature = "deflate-dictionary")]
+    pub fn set_deflate_dictionary(&mut self, dictionary: Option<&[u8]>) {
+        self.entry_settings.deflate_dictionary = dictionary.map(Arc::from);
+    }
+
+    /// Set whether files from the filesystem added after this call are memory-mapped instead of
+    /// read, which avoids copying them through buffers. Requires the `memmap2` feature.
+    ///
+    /// A mapped file must not be modified or truncated until it's compressed. Depending on the
+    /// platform, that can make the compressed data inconsistent or crash the process.
+    #[cfg(feature = "memmap2")]
+    pub fn set_memory_map(&mut self, memory_map: bool) {
+        self.entry_settings.memory_map = memory_map;
+    }
+
+    /// Set whether files added from slices or the filesystem after this call are stored without
+    /// compression if compressing doesn't make them smaller. Files added from readers can't be
+    /// read again, so they keep their compressed data even if it's larger than their contents.
+    pub fn set_auto_store(&mut self, auto_store: bool) {
+        self.entry_settings.auto_store = auto_store;
+    }
+
+    /// Set whether compressed data of files added after this call is kept in temporary files
+    /// instead of memory until the archive is written. This bounds memory use when archiving many
+    /// large files, at the cost of writing everything to disk twice. Each temporary file stays open
+    /// until the archive is written. Requires the `tempfile` feature.
+    #[cfg(feature = "tempfile")]
+    pub fn set_use_temp_files(&mut self, use_temp_files: bool) {
+        self.entry_settings.use_temp_files = use_temp_files;
+    }
+
+    /// Set whether entries added after this call get an extended timestamp extra field. It stores
+    /// the modification time as a Unix timestamp with 1 second resolution, and the access and
+    /// creation times of files from the filesystem, which plain MS-DOS timestamps can't represent.
+    pub fn set_extended_timestamps(&mut self, extended_timestamps: bool) {
+        self.entry_settings.extended_timestamps = extended_timestamps;
+    }
+
+    /// Set whether files, directories and symlinks from the filesystem added after this call get
+    /// an NTFS extra field, which Windows extractors use to restore the modification, access and
+    /// creation times with 100 nanosecond resolution. Entries with a time from
+    /// [`FileOptions::modified`] don't get the field.
+    pub fn set_ntfs_timestamps(&mut self, ntfs_timestamps: bool) {
+        self.entry_settings.ntfs_timestamps = ntfs_timestamps;
+    }
+
+    /// Set whether files, directories and symlinks from the filesystem added after this call
+    /// store the UID and GID of their owner in an Info-ZIP Unix extra field. Extractors running as
+    /// root can restore the ownership, others ignore it. Ownership is only available on Unix.
+    pub fn set_preserve_ownership(&mut self, preserve_ownership: bool) {
+        self.entry_settings.preserve_ownership = preserve_ownership;
+    }
+
+    /// Set a timestamp that is stored as the modification time of every entry added after this
+    /// call, instead of the time of the file or the current time. Access and creation times are
+    /// left out of extended timestamps. `None` goes back to the real times.
+    pub fn set_fixed_timestamp(&mut self, timestamp: Option<SystemTime>) {
+        self.entry_settings.fixed_timestamp = timestamp;
+    }
+
+    /// Set the latest timestamp stored for entries added after this call. Modification, access
+    /// and creation times after it are replaced with it, earlier ones are kept. `None` stores
+    /// every time as it is.
+    pub fn set_timestamp_clamp(&mut self, clamp: Option<SystemTime>) {
+        self.entry_settings.timestamp_clamp = clamp;
+    }
+
+    /// Clamp timestamps of entries added after this call to the `SOURCE_DATE_EPOCH` environment
+    /// variable, which reproducible build systems set to a number of seconds since the Unix epoch,
+    /// see [`ZipArchive::set_timestamp_clamp`]. Returns `false` and changes nothing if the variable
+    /// isn't set or isn't a valid number.
+    pub fn clamp_to_source_date_epoch(&mut self) -> bool {
+        let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|epoch| epoch.trim().parse().ok())
+        else {
+            return false;
+        };
+        self.set_timestamp_clamp(Some(UNIX_EPOCH + Duration::from_secs(epoch)));
+        true
+    }
+
+    /// Set whether entries added after this call are stored without anything that changes between
+    /// runs, so that the same inputs always produce the same archive. Entries get the timestamp
+    /// set with [`ZipArchive::set_fixed_timestamp`], or 1980-01-01 00:00:00 if there is none, and
+    /// files from the filesystem get 644 or 755 permissions depending on whether their owner can
+    /// execute them. Unless [`EntryOrder::Name`] is set, the archive is written in
+    /// [`EntryOrder::Insertion`] order.
+    ///
+    /// Names, contents, settings and the order in which entries are added still have to be the
+    /// same. [`ZipArchive::add_directory_recursive`] adds entries in the order the filesystem
+    /// lists them, use [`EntryOrder::Name`] if that isn't stable.
+    pub fn set_reproducible(&mut self, reproducible: bool) {
+        self.entry_settings.reproducible = reproducible;
+    }
+
+    /// Set the password that files added after this call are encrypted with, using the method
+    /// set with [`ZipArchive::set_encryption_method`]. `None` turns encryption off. Requires the
+    /// `aes` or the `zipcrypto` feature.
+    ///
+    /// Names, sizes and timestamps of entries are not encrypted. Directories, symlinks and entries
+    /// added with [`ZipArchive::add_empty_file`] are not encrypted either, and
+    /// [`BuiltArchive::verify`] skips encrypted entries.
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    pub fn set_password(&mut self, password: Option<&str>) {
+        self.entry_settings.password = password.map(|password| Password(password.into()));
+    }
+
+    /// Set how files added after this call are encrypted when a password is set. By default they
+    /// are encrypted with AES-256 if the `aes` feature is enabled.
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    pub fn set_encryption_method(&mut self, encryption_method: EncryptionMethod) {
+        self.entry_settings.encryption_method = encryption_method;
+    }
+
+    /// Set whether files added from memory after this call are marked as text if they are valid
+    /// UTF-8 without NUL bytes. Files from the filesystem and readers are not checked, use
+    /// [`FileOptions::text`] to mark them.
+    pub fn set_detect_text(&mut self, detect_text: bool) {
+        self.entry_settings.detect_text = detect_text;
+    }
+
+    /// Set what [`ZipArchive::add_directory_recursive`] does with symlinks. By default they are
+    /// skipped.
+    pub fn set_symlink_handling(&mut self, symlink_handling: SymlinkHandling) {
+        self.symlink_handling = symlink_handling;
+    }
+
+    /// Compress everything from `reader`, returning the compressed data, the CRC of the
+    /// uncompressed data and how many bytes were read
+    fn compress<R: Read>(
+        reader: R,
+        settings: &EntrySettings,
+    ) -> Result<(ZipFileData, u32, u64), std::io::Error> {
+        let reader = CancellableReader {
+            inner: reader,
+            cancel_token: &settings.cancel_token,
+        };
+        if settings.checksums_only {
+            let (crc, read) = Self::compress_to(reader, &mut std::io::sink(), &settings.stored())?;
+            return Ok((ZipFileData::Memory(Vec::new()), crc, read));
+        }
+        #[cfg(feature = "tempfile")]
+        if settings.use_temp_files {
+            let mut temp_file = tempfile::tempfile()?;
+            let (crc, read) = Self::compress_to(reader, &mut temp_file, settings)?;
+            let len = temp_file.stream_position()?;
+            return Ok((ZipFileData::TempFile(temp_file, len), crc, read));
+        }
+        let mut data = Vec::new();
+        let (crc, read) = Self::compress_to(reader, &mut data, settings)?;
+        Ok((ZipFileData::Memory(data), crc, read))
+    }
+
+    /// Compress everything from `reader` into `output`, returning the CRC of the uncompressed
+    /// data and how many bytes were read
+    fn compress_to<R: Read, W: Write>(
+        reader: R,
+        output: &mut W,
+        settings: &EntrySettings,
+    ) -> Result<(u32, u64), std::io::Error> {
+        // flate2 computes the CRC with crc32fast, which already picks a SIMD implementation like
+        // PCLMULQDQ at runtime when the CPU supports one
+        let mut crc_reader = CrcReader::new(CountingReader::new(reader));
+        encoder(settings).encode(&mut crc_reader, output)?;
+        Ok((crc_reader.crc().sum(), crc_reader.get_ref().count))
+    }
+
+    /// Compress `slice`, or store it instead if compressing doesn't help and auto-store is on.
+    /// Empty slices are always stored.
+    fn compress_slice(
+        slice: &[u8],
+        settings: &EntrySettings,
+    ) -> Result<(ZipFileData, u32, CompressionType), std::io::Error> {
+        if slice.is_empty() {
+            let (data, crc, _) = Self::compress(slice, &settings.stored())?;
+            return Ok((data, crc, CompressionType::Stored));
+        }
+        let (mut data, crc, _) = Self::compress(slice, settings)?;
+        let mut compression_type = settings.compression_type;
+        if settings.should_store_instead(data.len(), slice.len() as u64) {
+            (data, _, _) = Self::compress(slice, &settings.stored())?;
+            compression_type = CompressionType::Stored;
+        }
+        Ok((data, crc, compression_type))
+    }
+
+    /// Compress `file`, or store it instead if compressing doesn't help and auto-store is on.
+    /// Empty files are always stored. Returns the number of bytes actually read along with the
+    /// CRC, the file can be appended to or truncated after its metadata was read.
+    fn compress_file(
+        file: File,
+        metadata: &Metadata,
+        settings: &EntrySettings,
+    ) -> Result<(ZipFileData, u32, u64, CompressionType), std::io::Error> {
+        // Empty files can't be mapped on every platform
+        #[cfg(feature = "memmap2")]
+        if settings.memory_map && metadata.len() > 0 {
+            // SAFETY: the mapping is only read from. Modifying or truncating the file while it's
+            // mapped is documented as unsupported in `ZipArchive::set_memory_map`.
+            let map = unsafe { memmap2::Mmap::map(&file)? };
+            let (data, crc, compression_type) = Self::compress_slice(&map, settings)?;
+            return Ok((data, crc, map.len() as u64, compression_type));
+        }
+        #[cfg(not(feature = "memmap2"))]
+        let _ = metadata;
+
+        let (mut data, mut crc, mut read) = Self::compress(&file, settings)?;
+        let mut compression_type = settings.compression_type;
+        // Some extractors mishandle empty compressed streams, like the 2 bytes of an empty deflate
+        // stream
+        if read == 0 || settings.should_store_instead(data.len(), read) {
+            let mut file = file;
+            file.rewind()?;
+            (data, crc, read) = Self::compress(&file, &settings.stored())?;
+            compression_type = CompressionType::Stored;
+        }
+        Ok((data, crc, read, compression_type))
+    }
+
+    fn fs_file_to_archive_file(
+        file: File,
+        metadata: Metadata,
+        archived_name: &str,
+        settings: &EntrySettings,
+        options: FileOptions,
+    ) -> Result<ZipFile, ZipError> {
+        let (data, crc, uncompressed_size, compression_type) =
+            Self::compress_file(file, &metadata, settings)?;
+        let (data, encryption) = settings.encrypt(data, crc)?;
+        let ((mtime, extended_timestamp), ntfs_timestamp) = match options.modified {
+            Some(modified) => (settings.timestamps(Some(modified)), None),
+            None => (
+                settings.fs_timestamps(&metadata),
+                settings.fs_ntfs_timestamp(&metadata),
+            ),
+        };
+        let owner = settings.fs_owner(&metadata);
+        let external_file_attributes = options.external_attributes.unwrap_or_else(|| {
+            if settings.reproducible {
+                reproducible_file_attributes(&metadata)
+            } else {
+                fs_file_attributes(&metadata)
+            }
+        });
+        Ok(ZipFile {
+            compression_type,
+            crc,
+            uncompressed_size,
+            mtime,
+            extended_timestamp,
+            ntfs_timestamp,
+            filename: archived_name.into(),
+            comment: options.comment,
+            data,
+            external_file_attributes,
+            text: options.text.unwrap_or(false),
+            encryption,
+            owner,
+            compression_level: Some(settings.compression_level.level()),
+            preset_dictionary: settings.has_dictionary(compression_type),
+            sequence: 0,
+        })
+    }
+
+    fn slice_to_archive_file(
+        slice: &[u8],
+        archived_name: &str,
+        settings: &EntrySettings,
+        options: FileOptions,
+    ) -> Result<ZipFile, ZipError> {
+        let uncompressed_size = slice.len() as u64;
+        let (data, crc, compression_type) = Self::compress_slice(slice, settings)?;
+        let (data, encryption) = settings.encrypt(data, crc)?;
+        let text = options
+            .text
+            .unwrap_or_else(|| settings.detect_text && is_text(slice));
+        let (mtime, extended_timestamp) = settings.timestamps(options.modified);
+        Ok(ZipFile {
+            compression_type,
+            crc,
+            uncompressed_size,
+            mtime,
+            extended_timestamp,
+            ntfs_timestamp: None,
+            filename: archived_name.into(),
+            comment: options.comment,
+            data,
+            external_file_attributes: options
+                .external_attributes
+                .unwrap_or(DEFAULT_FILE_ATTRIBUTES),
+            text,
+            encryption,
+            owner: None,
+            compression_level: Some(settings.compression_level.level()),
+            preset_dictionary: settings.has_dictionary(compression_type),
+            sequence: 0,
+        })
+    }
+
+    fn reader_to_archive_file<R: Read>(
+        reader: R,
+        archived_name: &str,
+        settings: &EntrySettings,
+        options: FileOptions,
+    ) -> Result<ZipFile, ZipError> {
+        let (mut data, crc, uncompressed_size) = Self::compress(reader, settings)?;
+        let mut compression_type = settings.compression_type;
+        // Nothing was read, so the empty compressed stream can be replaced without reading again
+        if uncompressed_size == 0 {
+            data = ZipFileData::Memory(Vec::new());
+            compression_type = CompressionType::Stored;
+        }
+        let (data, encryption) = settings.encrypt(data, crc)?;
+        let (mtime, extended_timestamp) = settings.timestamps(options.modified);
+        Ok(ZipFile {
+            compression_type,
+            crc,
+            uncompressed_size,
+            mtime,
+            extended_timestamp,
+            ntfs_timestamp: None,
+            filename: archived_name.into(),
+            comment: options.comment,
+            data,
+            external_file_attributes: options
+                .external_attributes
+                .unwrap_or(DEFAULT_FILE_ATTRIBUTES),
+            text: options.text.unwrap_or(false),
+            encryption,
+            owner: None,
+            compression_level: Some(settings.compression_level.level()),
+            preset_dictionary: settings.has_dictionary(compression_type),
+            sequence: 0,
+        })
+    }
+
+    /// Add a file from the filesystem. The file is opened on the calling thread, so a missing or
+    /// unreadable file, or a directory, is reported here instead of on the thread pool.
+    /// Compression itself still happens on the pool, errors while reading the file are returned
+    /// from [`ZipArchive::write`].
+    pub fn add_file_from_fs(
+        &mut self,
+        fs_path: &Path,
+        archived_name: &str,
+    ) -> Result<EntryId, ZipError> {
+        self.add_file_from_fs_with(fs_path, archived_name, FileOptions::default())
+    }
+
+    /// Add a file from the filesystem, named by its path relative to `base_dir` with forward
+    /// slashes as separators. Fails if `fs_path` isn't inside `base_dir` or is `base_dir` itself,
+    /// which is checked on the paths as they are given, without resolving symlinks. Returns
+    /// [`ZipError::UnsafePath`] if the relative path contains `..`.
+    pub fn add_file_from_fs_relative(
+        &mut self,
+        fs_path: &Path,
+        base_dir: &Path,
+    ) -> Result<EntryId, ZipError> {
+        let relative = fs_path
+            .strip_prefix(base_dir)
+            .ok()
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} is not inside {}", fs_path.display(), base_dir.display()),
+                )
+            })?;
+        let mut archived_name = String::new();
+        for component in relative.components() {
+            let Component::Normal(component) = component else {
+                return Err(ZipError::UnsafePath(relative.display().to_string()));
+            };
+            let component = component.to_str().ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("file name is not valid UTF-8: {}", fs_path.display()),
+                )
+            })?;
+            if !archived_name.is_empty() {
+                archived_name.push('/');
+            }
+            archived_name.push_str(component);
+        }
+        self.add_file_from_fs(fs_path, &archived_name)
+    }
+
+    /// Add a file from the filesystem with the given entry options, see
+    /// [`ZipArchive::add_file_from_fs`]
+    pub fn add_file_from_fs_with(
+        &mut self,
+        fs_path: &Path,
+        archived_name: &str,
+        options: FileOptions,
+    ) -> Result<EntryId, ZipError> {
+        let archived_name = self.entry_name(archived_name)?;
+        options.check()?;
+        // Checked before opening, opening a directory fails with an I/O error on some platforms
+        // and succeeds on others, where reading it then fails or returns nothing
+        if std::fs::metadata(fs_path)?.is_dir() {
+            return Err(ZipError::ExpectedFile(fs_path.display().to_string()));
+        }
+        let file = File::open(fs_path)?;
+        let metadata = file.metadata()?;
+        let settings = self.entry_settings_for(&options);
+        let id = self.spawn_entry(archived_name, move |archived_name| {
+            Self::fs_file_to_archive_file(file, metadata, archived_name, &settings, options)
+        });
+        Ok(id)
+    }
+
+    /// Add a file from binary slice. The data is copied so it can be moved to the thread pool, use
+    /// [`ZipArchive::add_file_from_owned`] to avoid the copy.
+    pub fn add_file_from_slice(
+        &mut self,
+        slice: &[u8],
+        archived_name: &str,
+    ) -> Result<EntryId, ZipError> {
+        self.add_file_from_owned(slice.to_vec(), archived_name)
+    }
+
+    /// Add a file from binary slice with the given entry options, see
+    /// [`ZipArchive::add_file_from_slice`]
+    pub fn add_file_from_slice_with(
+        &mut self,
+        slice: &[u8],
+        archived_name: &str,
+        options: FileOptions,
+    ) -> Result<EntryId, ZipError> {
+        self.add_file_from_owned_with(slice.to_vec(), archived_name, options)
+    }
+
+    /// Add a file from binary slice with the given modification time, for generated data that has
+    /// no real one. See [`FileOptions::modified`].
+    pub fn add_file_from_slice_with_time(
+        &mut self,
+        slice: &[u8],
+        archived_name: &str,
+        modified: SystemTime,
+    ) -> Result<EntryId, ZipError> {
+        self.add_file_from_slice_with(
+            slice,
+            archived_name,
+            FileOptions::default().modified(modified),
+        )
+    }
+
+    /// Add a file from data that can be moved to the thread pool without copying it, like a
+    /// `Vec<u8>`, an `Arc<[u8]>` or a `&'static [u8]`.
+    pub fn add_file_from_owned<T: AsRef<[u8]> + Send + 'static>(
+        &mut self,
+        data: T,
+        archived_name: &str,
+    ) -> Result<EntryId, ZipError> {
+        self.add_file_from_owned_with(data, archived_name, FileOptions::default())
+    }
+
+    /// Add a file from owned data with the given entry options, see
+    /// [`ZipArchive::add_file_from_owned`]
+    pub fn add_file_from_owned_with<T: AsRef<[u8]> + Send + 'static>(
+        &mut self,
+        data: T,
+        archived_name: &str,
+        options: FileOptions,
+    ) -> Result<EntryId, ZipError> {
+        let archived_name = self.entry_name(archived_name)?;
+        options.check()?;
+        let settings = self.entry_settings_for(&options);
+        let id = self.spawn_entry(archived_name, move |archived_name| {
+            Self::slice_to_archive_file(data.as_ref(), archived_name, &settings, options)
+        });
+        Ok(id)
+    }
+
+    /// Add a file with the contents of `reader`, like a socket or a decompressing reader. The
+    /// reader is moved to the thread pool and read to the end there, without buffering the
+    /// uncompressed data, and read errors are returned from [`ZipArchive::write`]. The data can't
+    /// be read twice, so [`ZipArchive::set_auto_store`] doesn't apply to these files.
+    pub fn add_file_from_reader<R: Read + Send + 'static>(
+        &mut self,
+        reader: R,
+        archived_name: &str,
+    ) -> Result<EntryId, ZipError> {
+        self.add_file_from_reader_with(reader, archived_name, FileOptions::default())
+    }
+
+    /// Add a file from a reader with the given entry options, see
+    /// [`ZipArchive::add_file_from_reader`]
+    pub fn add_file_from_reader_with<R: Read + Send + 'static>(
+        &mut self,
+        reader: R,
+        archived_name: &str,
+        options: FileOptions,
+    ) -> Result<EntryId, ZipError> {
+        let archived_name = self.entry_name(archived_name)?;
+        options.check()?;
+        let settings = self.entry_settings_for(&options);
+        let id = self.spawn_entry(archived_name, move |archived_name| {
+            Self::reader_to_archive_file(reader, archived_name, &settings, options)
+        });
+        Ok(id)
+    }
+
+    /// Add a file from data that was already compressed with `compression_type`, like a cached
+    /// blob. `crc` and `uncompressed_size` describe the uncompressed data and are written as they
+    /// are, wrong values make extractors report the entry as corrupt. Nothing is compressed, so
+    /// the thread pool is not used, and the archive's compression, auto-store and encryption
+    /// settings don't apply.
+    pub fn add_precompressed(
+        &mut self,
+        data: Vec<u8>,
+        compression_type: CompressionType,
+        crc: u32,
+        uncompressed_size: u64,
+        archived_name: &str,
+    ) -> Result<EntryId, ZipError> {
+        self.add_precompressed_with(
+            data,
+            compression_type,
+            crc,
+            uncompressed_size,
+            archived_name,
+            FileOptions::default(),
+        )
+    }
+
+    /// Add compressed data with the given entry options, see [`ZipArchive::add_precompressed`].
+    /// The compression type of `options` is ignored.
+    pub fn add_precompressed_with(
+        &mut self,
+        data: Vec<u8>,
+        compression_type: CompressionType,
+        crc: u32,
+        uncompressed_size: u64,
+        archived_name: &str,
+        options: FileOptions,
+    ) -> Result<EntryId, ZipError> {
+        let archived_name = self.entry_name(archived_name)?;
+        options.check()?;
+        let (mtime, extended_timestamp) = self.entry_settings.timestamps(options.modified);
+        let file = ZipFile {
+            compression_type,
+            crc,
+            uncompressed_size,
+            mtime,
+            extended_timestamp,
+            ntfs_timestamp: None,
+            filename: archived_name,
+            comment: options.comment,
+            data: ZipFileData::Memory(data),
+            external_file_attributes: options
+                .external_attributes
+                .unwrap_or(DEFAULT_FILE_ATTRIBUTES),
+            text: options.text.unwrap_or(false),
+            encryption: None,
+            owner: None,
+            compression_level: None,
+            preset_dictionary: false,
+            sequence: 0,
+        };
+        Ok(self.send_entry(file))
+    }
+
+    /// Add `data` as a stored entry with its CRC already known, like data copied out of another
+    /// archive. The CRC isn't computed again, see [`ZipArchive::add_precompressed`].
+    pub fn add_stored_with_crc(
+        &mut self,
+        data: Vec<u8>,
+        archived_name: &str,
+        crc: u32,
+    ) -> Result<EntryId, ZipError> {
+        let uncompressed_size = data.len() as u64;
+        self.add_precompressed(
+            data,
+            CompressionType::Stored,
+            crc,
+            uncompressed_size,
+            archived_name,
+        )
+    }
+
+    /// Add an empty directory entry. A slash is appended to the name if it doesn't end with one.
+    /// Use [`ZipArchive::add_directory_from_fs`] to give it the permissions and timestamps of a
+    /// directory on the filesystem.
+    pub fn add_directory(&mut self, archived_name: &str) -> Result<EntryId, ZipError> {
+        let compressed_file = ZipFile::directory(
+            self.directory_name(archived_name)?,
+            self.entry_settings.now(),
+            self.entry_settings.extended_timestamps,
+        );
+        Ok(self.send_entry(compressed_file))
+    }
+
+    /// Add a directory entry with the permissions and timestamps of the directory at `fs_path`.
+    /// Only the directory itself is added, not its contents. In reproducible mode the
+    /// permissions are always 755.
+    pub fn add_directory_from_fs(
+        &mut self,
+        fs_path: &Path,
+        archived_name: &str,
+    ) -> Result<EntryId, ZipError> {
+        let metadata = std::fs::metadata(fs_path)?;
+        let mut directory = ZipFile::directory(
+            self.directory_name(archived_name)?,
+            SystemTime::now(),
+            false,
+        );
+        (directory.mtime, directory.extended_timestamp) =
+            self.entry_settings.fs_timestamps(&metadata);
+        directory.ntfs_timestamp = self.entry_settings.fs_ntfs_timestamp(&metadata);
+        if !self.entry_settings.reproducible {
+            directory.external_file_attributes = fs_directory_attributes(&metadata);
+        }
+        directory.owner = self.entry_settings.fs_owner(&metadata);
+        Ok(self.send_entry(directory))
+    }
+
+    /// Turn a name given to an add method into the name of a directory entry
+    fn directory_name(&self, archived_name: &str) -> Result<String, ZipError> {
+        let archived_name = self.entry_name(archived_name)?;
+        // A slash is appended to directory names that don't end with one
+        if archived_name.len() == u16::MAX as usize && !archived_name.ends_with('/') {
+            return Err(ZipError::NameTooLong);
+        }
+        Ok(archived_name)
+    }
+
+    /// Add a file with no contents. There is nothing to compress, so the thread pool is not used.
+    pub fn add_empty_file(&mut self, archived_name: &str) -> Result<EntryId, ZipError> {
+        let archived_name = self.entry_name(archived_name)?;
+        let compressed_file = ZipFile::empty_file(
+            archived_name,
+            self.entry_settings.now(),
+            self.entry_settings.extended_timestamps,
+        );
+        Ok(self.send_entry(compressed_file))
+    }
+
+    /// Add a symlink entry pointing to `target`. Extractors that support symlinks recreate it as a
+    /// symlink, others extract a file containing the target path. `target` is stored as is, name
+    /// sanitization only applies to `archived_name`.
+    pub fn add_symlink(&mut self, target: &str, archived_name: &str) -> Result<EntryId, ZipError> {
+        let archived_name = self.entry_name(archived_name)?;
+        let symlink = ZipFile::symlink(
+            archived_name,
+            target,
+            self.entry_settings.now(),
+            self.entry_settings.extended_timestamps,
+        );
+        Ok(self.send_entry(symlink))
+    }
+
+    /// Add a directory from the filesystem with all of its contents. Entries are named by their
+    /// path relative to `fs_root`, joined onto `archive_prefix`. If `archive_prefix` isn't empty, a
+    /// directory entry is added for it as well. Directory entries get the permissions and
+    /// timestamps of the directories, like [`ZipArchive::add_directory_from_fs`]. Symlinks are
+    /// handled as set with [`ZipArchive::set_symlink_handling`], anything else that isn't a regular
+    /// file or a directory is skipped. Paths can be left out with [`ZipArchive::set_entry_filter`].
+    pub fn add_directory_recursive(
+        &mut self,
+        fs_root: &Path,
+        archive_prefix: &str,
+    ) -> Result<(), ZipError> {
+        let archive_prefix = archive_prefix.trim_end_matches(['/', '\\']);
+        if !archive_prefix.is_empty() {
+            self.add_directory_from_fs(fs_root, archive_prefix)?;
+        }
+        self.add_directory_contents(fs_root, archive_prefix)
+    }
+
+    fn add_directory_contents(&mut self, fs_dir: &Path, archive_dir: &str) -> Result<(), ZipError> {
+        for entry in fs_dir.read_dir()? {
+            let entry = entry?;
+            let fs_path = entry.path();
+            let mut file_type = entry.file_type()?;
+            let mut symlink_metadata = None;
+            let mut target_metadata = None;
+            if file_type.is_symlink() {
+                match self.symlink_handling {
+                    SymlinkHandling::Skip => continue,
+                    SymlinkHandling::Follow => {
+                        let metadata = std::fs::metadata(&fs_path)?;
+                        file_type = metadata.file_type();
+                        target_metadata = Some(metadata);
+                    }
+                    // Metadata of the entry is the metadata of the link itself
+                    SymlinkHandling::Store => symlink_metadata = Some(entry.metadata()?),
+                }
+            }
+            if let Some(EntryFilter(filter)) = &self.entry_filter {
+                let metadata = match (&symlink_metadata, target_metadata) {
+                    (Some(metadata), _) => metadata.clone(),
+                    (None, Some(metadata)) => metadata,
+                    (None, None) => entry.metadata()?,
+                };
+                if !filter(&fs_path, &metadata) {
+                    continue;
+                }
+            }
+
+            let file_name = entry.file_name();
+            let file_name = file_name.to_str().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("file name is not valid UTF-8: {}", fs_path.display()),
+                )
+            })?;
+            let archived_name = if archive_dir.is_empty() {
+                file_name.to_string()
+            } else {
+                format!("{archive_dir}/{file_name}")
+            };
+
+            if let Some(metadata) = symlink_metadata {
+                let target = std::fs::read_link(&fs_path)?;
+                let target = target.to_str().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("symlink target is not valid UTF-8: {}", fs_path.display()),
+                    )
+                })?;
+                let archived_name = self.entry_name(&archived_name)?;
+                let modified = self.entry_settings.clamp_time(
+                    self.entry_settings.fixed_time().unwrap_or_else(|| {
+                        metadata.modified().unwrap_or_else(|_| SystemTime::now())
+                    }),
+                );
+                let mut symlink = ZipFile::symlink(
+                    archived_name,
+                    target,
+                    modified,
+                    self.entry_settings.extended_timestamps,
+                );
+                symlink.ntfs_timestamp = self.entry_settings.fs_ntfs_timestamp(&metadata);
+                symlink.owner = self.entry_settings.fs_owner(&metadata);
+                self.send_entry(symlink);
+            } else if file_type.is_dir() {
+                self.add_directory_from_fs(&fs_path, &archived_name)?;
+                self.add_directory_contents(&fs_path, &archived_name)?;
+            } else if file_type.is_file() {
+                self.add_file_from_fs(&fs_path, &archived_name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Abandon the archive. Files that haven't started compressing yet are skipped, the ones that
+    /// are being compressed are finished and thrown away. Dropping the archive does the same.
+    pub fn cancel(self) {}
+
+    /// Wait for all files to be compressed and write the archive to `destination`
+    ///
+    /// The archive starts at the current position of `destination`, and offsets in the headers
+    /// are counted from the start of `destination`. Writing a stub executable first turns the
+    /// result into a self-extracting archive that extractors read without adjusting offsets.
+    pub fn write<W: Write + Seek>(self, destination: &mut W) -> Result<ArchiveStats, ZipError> {
+        self.build()?.write_to(destination)
+    }
+
+    /// Wait for all files to be compressed and write the archive to `file`, setting its length to
+    /// the end of the archive, see [`BuiltArchive::write_to_file`]. This is the way to write an
+    /// archive opened with [`ZipArchive::append`] back to its file.
+    pub fn write_to_file(self, file: &mut File) -> Result<ArchiveStats, ZipError> {
+        self.build()?.write_to_file(file)
+    }
+
+    /// Wait for all files to be compressed and write the archive to `destination`, which is
+    /// returned afterwards, like a `Cursor` to take the written buffer out of
+    pub fn write_into<W: Write + Seek>(self, mut destination: W) -> Result<W, ZipError> {
+        self.write(&mut destination)?;
+        Ok(destination)
+    }
+
+    /// Wait for all files to be compressed and write the archive to memory, for serving it or
+    /// embedding it somewhere. When appending, the bytes go where the old central directory
+    /// started.
+    pub fn to_bytes(self) -> Result<Vec<u8>, ZipError> {
+        self.build()?.to_bytes()
+    }
+
+    /// Wait for all files to be compressed and write the archive to a destination that can't
+    /// seek, like a pipe or a socket. Every entry is followed by a data descriptor, so some old
+    /// extractors that can't handle those may fail to read the archive.
+    ///
+    /// When appending, `destination` has to continue exactly where the old central directory
+    /// started.
+    pub fn write_streaming<W: Write>(self, destination: &mut W) -> Result<ArchiveStats, ZipError> {
+        self.build()?.write_streaming_to(destination)
+    }
+
+    /// Write the archive to `destination` while files are still being compressed. Every entry is
+    /// written as soon as it's done and its compressed data is dropped, so only the central
+    /// directory is kept in memory instead of the whole archive. Together with
+    /// [`ZipArchive::with_capacity`] this keeps memory use bounded for archives of any size.
+    ///
+    /// Entries end up in the order they finish compressing, [`ZipArchive::set_entry_order`] and
+    /// the ordering of [`ZipArchive::set_reproducible`] don't apply. If an entry fails or has a
+    /// duplicate name, the entries before it have already been written. When appending,
+    /// `destination` has to continue exactly where the old central directory started.
+    pub fn write_incremental<W: Write>(
+        self,
+        destination: &mut W,
+    ) -> Result<ArchiveStats, ZipError> {
+        let Self {
+            thread_pool,
+            sequential,
+            tx,
+            rx,
+            entry_settings,
+            symlink_handling: _,
+            allow_duplicates,
+            reject_empty,
+            name_sanitization: _,
+            entry_order: _,
+            version_made_by,
+            dos_attributes,
+            attribute_mode,
+            comment,
+            existing_entries,
+            start_offset,
+            progress,
+            name_mapper: _,
+            entry_filter: _,
+            queued: _,
+            collected,
+            received: _,
+            failed,
+            has_checksums_only,
+            // Kept until the end, so that jobs left after an error are cancelled
+            cancelled: _cancelled,
+            job_limit: _,
+        } = self;
+        drop(tx);
+        if entry_settings.cancel_token.is_cancelled() {
+            return Err(ZipError::Cancelled);
+        }
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        if has_checksums_only {
+            return Err(ZipError::ChecksumsOnly);
+        }
+
+        let mut names: Option<HashSet<String>> = (!allow_duplicates).then(|| {
+            existing_entries
+                .iter()
+                .map(|entry| entry.filename.clone())
+                .collect()
+        });
+        // Headers are written one field at a time, see `BuiltArchive::write_buffered`
+        let mut destination = PositionWriter::new(BufWriter::new(destination), start_offset);
+        let mut files = Vec::new();
+        let mut locations = Vec::new();
+        for file in collected.into_iter().map(Ok).chain(rx.iter()) {
+            let mut file = file?;
+            file.apply_attribute_mode(attribute_mode, dos_attributes);
+            if let Some(names) = &mut names {
+                if !names.insert(file.filename.clone()) {
+                    return Err(ZipError::DuplicateName(file.filename));
+                }
+            }
+            let offset = destination.position();
+            file.to_bytes_filerecord(&mut destination, offset, false)?;
+            locations.push((0, offset));
+            file.data = ZipFileData::Written(file.data.len());
+            if let Some(ProgressCallback(progress)) = &progress {
+                progress(ProgressEvent {
+                    id: EntryId(file.sequence),
+                    filename: &file.filename,
+                    completed: files.len() + 1,
+                });
+            }
+            files.push(file);
+        }
+        if reject_empty && existing_entries.is_empty() && files.is_empty() {
+            return Err(ZipError::EmptyArchive);
+        }
+
+        let archive = BuiltArchive {
+            files,
+            existing_entries,
+            start_offset,
+            version_made_by,
+            comment,
+            progress,
+            thread_pool,
+            sequential,
+        };
+        let stats = archive.write_central_directory(&mut destination, &locations, false)?;
+        destination.flush()?;
+        Ok(stats)
+    }
+
+    /// Wait for all files to be compressed and write the archive split into volumes of at most
+    /// `volume_size` bytes, for targets with a size limit. `make_writer` is called with the index
+    /// of every volume, starting at 0, when writing reaches it. By convention volumes are named
+    /// `name.z01`, `name.z02` and so on, except for the last one, which is `name.zip`, so rename
+    /// the last one afterwards. If the archive fits in a single volume, it's written as a regular
+    /// archive.
+    ///
+    /// The volume size is at least 64 KiB. Headers are never split between volumes, so volumes
+    /// can end up slightly smaller. Appended archives can't be split. Not every extractor supports
+    /// split archives, Info-ZIP's `zip -s 0` can join the volumes into a regular archive.
+    pub fn write_split<W, F>(
+        self,
+        volume_size: u64,
+        make_writer: F,
+    ) -> Result<ArchiveStats, ZipError>
+    where
+        W: Write,
+        F: FnMut(usize) -> Result<W, std::io::Error>,
+    {
+        self.build()?.write_split_to(volume_size, make_writer)
+    }
+
+    /// Wait for all files to be compressed and check the entries, without writing anything yet.
+    /// The resulting [`BuiltArchive`] can be written any number of times.
+    pub fn build(self) -> Result<BuiltArchive<'a>, ZipError> {
+        let Self {
+            thread_pool,
+            sequential,
+            tx,
+            rx,
+            entry_settings,
+            symlink_handling: _,
+            allow_duplicates,
+            reject_empty,
+            name_sanitization: _,
+            entry_order,
+            version_made_by,
+            dos_attributes,
+            attribute_mode,
+            comment,
+            existing_entries,
+            start_offset,
+            progress,
+            name_mapper: _,
+            entry_filter: _,
+            queued: _,
+            collected,
+            received: _,
+            failed,
+            has_checksums_only,
+            // Kept until the end, so that jobs left after an error are cancelled
+            cancelled: _cancelled,
+            job_limit: _,
+        } = self;
+        drop(tx);
+        if entry_settings.cancel_token.is_cancelled() {
+            return Err(ZipError::Cancelled);
+        }
+        if let Some(e) = failed {
+            return Err(e);
+        }
+
+        let mut files = collected;
+        // Stops at the first failed entry, the remaining ones are dropped
+        for file in rx.iter() {
+            files.push(file?);
+        }
+        if has_checksums_only {
+            return Err(ZipError::ChecksumsOnly);
+        }
+        sort_entries(&mut files, entry_order, entry_settings.reproducible);
+        for file in &mut files {
+            file.apply_attribute_mode(attribute_mode, dos_attributes);
+        }
+        let entry_count = existing_entries.len() + files.len();
+        if reject_empty && entry_count == 0 {
+            return Err(ZipError::EmptyArchive);
+        }
+        if !allow_duplicates {
+            let mut names = HashSet::with_capacity(entry_count);
+            let duplicate = existing_entries
+                .iter()
+                .map(|entry| &entry.filename)
+                .chain(files.iter().map(|file| &file.filename))
+                .find(|&name| !names.insert(name));
+            if let Some(name) = duplicate {
+                return Err(ZipError::DuplicateName(name.clone()));
+            }
+        }
+
+        Ok(BuiltArchive {
+            files,
+            existing_entries,
+            start_offset,
+            version_made_by,
+            comment,
+            progress,
+            thread_pool,
+            sequential,
+        })
+    }
+}
+
+/// Archive with all of its entries compressed, created by [`ZipArchive::build`]. Writing it
+/// doesn't consume it, so the same archive can be written to several destinations without
+/// compressing anything again.
+#[derive(Debug)]
+pub struct BuiltArchive<'a> {
+    files: Vec<ZipFile>,
+    /// Entries of the archive that is being appended to
+    existing_entries: Vec<CentralDirectoryEntry>,
+    /// Offset at which new entries are written, the old central directory offset when appending
+    start_offset: u64,
+    version_made_by: u16,
+    comment: Vec<u8>,
+    progress: Option<ProgressCallback<'a>>,
+    /// Pool of the archive it was built from, used for serializing the central directory
+    thread_pool: Option<&'a ThreadPool>,
+    sequential: bool,
+}
+
+impl BuiltArchive<'_> {
+    /// Decompress every entry and check that the CRC and size of the result match the ones that
+    /// will be written to the archive, using rayon's current thread pool. Returns
+    /// [`ZipError::CorruptEntry`] for the first entry that doesn't match. Entries that were already
+    /// in an archive that is appended to, encrypted entries and entries deflated with a preset
+    /// dictionary, which can't be inflated without it, are not checked.
+    pub fn verify(&self) -> Result<(), ZipError> {
+        self.files.par_iter().try_for_each(ZipFile::verify)
+    }
+
+    /// Write the archive to memory, parse it back and check that every entry has the name, CRC
+    /// and sizes it was written with, extracting the ones that aren't encrypted or deflated with a
+    /// preset dictionary. Unlike [`BuiltArchive::verify`] this also checks the headers, so it's
+    /// meant for testing and fuzzing the writer rather than for every archive. The archive is
+    /// written as if it started at offset 0, entries that were already in an archive that is
+    /// appended to are not checked.
+    pub fn verify_roundtrip(&self) -> Result<(), ZipError> {
+        let mut bytes = Vec::with_capacity(self.written_size(0, false) as usize);
+        self.write_archive(&mut PositionWriter::new(&mut bytes, 0), false)?;
+        let mut archive = Cursor::new(bytes);
+        let central_directory = CentralDirectory::read(&mut archive)?;
+        if central_directory.entries.len() != self.existing_entries.len() + self.files.len() {
+            return Err(ZipError::InvalidArchive("wrong number of entries"));
+        }
+        let written = &central_directory.entries[self.existing_entries.len()..];
+        for (file, entry) in self.files.iter().zip(written) {
+            if entry.filename != file.filename
+                || entry.crc != file.header_crc()
+                || entry.compressed_size != file.compressed_size()
+                || entry.uncompressed_size != file.uncompressed_size
+            {
+                return Err(ZipError::CorruptEntry(file.filename.clone()));
+            }
+            if file.encryption.is_none() && !file.preset_dictionary {
+                entry.extract(&mut archive)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Information about every entry, in the order they are written, starting with the entries
+    /// of an archive that is appended to
+    pub fn entries(&self) -> Vec<EntryInfo> {
+        self.existing_entries
+            .iter()
+            .map(CentralDirectoryEntry::info)
+            .chain(self.files.iter().map(ZipFile::info))
+            .collect()
+    }
+
+    /// Find entries that have the same contents, so they can be pruned or turned into links. Zip
+    /// can't store the same data once for several entries, so they are only reported. Contents
+    /// are compared by their CRC-32 and size, which were computed during compression, so in rare
+    /// cases different contents are reported as the same. Empty entries are ignored.
+    pub fn duplicate_report(&self) -> Vec<DuplicateContents> {
+        let mut groups: HashMap<(u32, u64), Vec<String>> = HashMap::new();
+        let mut order = Vec::new();
+        let entries = self
+            .existing_entries
+            .iter()
+            .map(|entry| (entry.crc, entry.uncompressed_size, &entry.filename))
+            .chain(
+                self.files
+                    .iter()
+                    .map(|file| (file.crc, file.uncompressed_size, &file.filename)),
+            );
+        for (crc, uncompressed_size, name) in entries {
+            if uncompressed_size == 0 {
+                continue;
+            }
+            let names = groups.entry((crc, uncompressed_size)).or_default();
+            if names.is_empty() {
+                order.push((crc, uncompressed_size));
+            }
+            names.push(name.clone());
+        }
+        order
+            .into_iter()
+            .filter_map(|key| {
+                let names = groups.remove(&key)?;
+                (names.len() > 1).then_some(DuplicateContents {
+                    crc: key.0,
+                    uncompressed_size: key.1,
+                    names,
+                })
+            })
+            .collect()
+    }
+
+    /// Size of the whole archive file that [`BuiltArchive::write_to`] produces when writing from
+    /// the start of the destination, or from where the old central directory started when
+    /// appending. Nothing is compressed or read to compute it.
+    pub fn total_size(&self) -> u64 {
+        self.start_offset + self.written_size(self.start_offset, false)
+    }
+
+    /// Number of bytes [`BuiltArchive::write_streaming_to`] writes, which is larger than
+    /// [`BuiltArchive::total_size`] because of the data descriptors, like for a `Content-Length`
+    /// header before streaming the archive. When appending, only the bytes written after the old
+    /// entries are counted.
+    pub fn streaming_size(&self) -> u64 {
+        self.written_size(self.start_offset, true)
+    }
+
+    /// Write the archive to `file`, see [`ZipArchive::write`]. The file's length is set to the
+    /// final size first, which lets the filesystem allocate the space at once. Anything that was
+    /// in the file after the end of the archive is cut off.
+    pub fn write_to_file(&self, file: &mut File) -> Result<ArchiveStats, ZipError> {
+        let archive_start = file.stream_position()?;
+        file.set_len(archive_start + self.written_size(archive_start, false))?;
+        self.write_to(file)
+    }
+
+    /// Write the archive to `destination`, see [`ZipArchive::write`]
+    pub fn write_to<W: Write + Seek>(&self, destination: &mut W) -> Result<ArchiveStats, ZipError> {
+        let archive_start = destination.stream_position()?;
+        self.write_buffered(destination, archive_start, false)
+    }
+
+    /// Write the archive to memory, see [`ZipArchive::to_bytes`]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ZipError> {
+        let mut bytes = Vec::with_capacity(self.written_size(self.start_offset, false) as usize);
+        self.write_archive(
+            &mut PositionWriter::new(&mut bytes, self.start_offset),
+            false,
+        )?;
+        Ok(bytes)
+    }
+
+    /// Write the archive split into volumes, see [`ZipArchive::write_split`]
+    pub fn write_split_to<W, F>(
+        &self,
+        volume_size: u64,
+        mut make_writer: F,
+    ) -> Result<ArchiveStats, ZipError>
+    where
+        W: Write,
+        F: FnMut(usize) -> Result<W, std::io::Error>,
+    {
+        if !self.existing_entries.is_empty() || self.start_offset != 0 {
+            return Err(ZipError::CannotSplit("appended archives can't be split"));
+        }
+        let volume_size = volume_size.max(MIN_VOLUME_SIZE);
+        if self.written_size(0, false) <= volume_size {
+            return self.write_buffered(make_writer(0)?, 0, false);
+        }
+        let make_writer = |volume| make_writer(volume).map(BufWriter::new);
+        let mut destination = SplitWriter::new(volume_size, make_writer)?;
+        destination.write_all(&SPLIT_ARCHIVE_SIGNATURE.to_le_bytes())?;
+        let stats = self.write_archive(&mut destination, false)?;
+        destination.flush()?;
+        Ok(stats)
+    }
+
+    /// Write the archive to a destination that can't seek, see [`ZipArchive::write_streaming`]
+    pub fn write_streaming_to<W: Write>(
+        &self,
+        destination: &mut W,
+    ) -> Result<ArchiveStats, ZipError> {
+        self.write_buffered(destination, self.start_offset, true)
+    }
+
+    /// Write the archive through a buffer, since headers are written one field at a time, which
+    /// would be a system call per field on an unbuffered file or socket. The position of
+    /// `destination` is only read before writing starts, so it doesn't have to see the bytes
+    /// that are still buffered.
+    fn write_buffered<W: Write>(
+        &self,
+        destination: W,
+        archive_start: u64,
+        data_descriptors: bool,
+    ) -> Result<ArchiveStats, ZipError> {
+        let mut destination = PositionWriter::new(BufWriter::new(destination), archive_start);
+        let stats = self.write_archive(&mut destination, data_descriptors)?;
+        destination.flush()?;
+        Ok(stats)
+    }
+
+    /// Number of bytes [`BuiltArchive::write_archive`] writes when starting at `archive_start`
+    fn written_size(&self, archive_start: u64, data_descriptors: bool) -> u64 {
+        let mut position = archive_start;
+        let mut central_dir_size: u64 = self
+            .existing_entries
+            .iter()
+            .map(|entry| entry.raw.len() as u64)
+            .sum();
+        for file in &self.files {
+            central_dir_size += file.direntry_size(position);
+            position += file.filerecord_size(file.has_data_descriptor(data_descriptors));
+        }
+        let central_dir_offset = position;
+        position += central_dir_size;
+        let entry_count = self.existing_entries.len() + self.files.len();
+        if needs_zip64_end_of_central_dir(entry_count, central_dir_offset, central_dir_size) {
+            position += ZIP64_END_OF_CENTRAL_DIR_TOTAL_SIZE;
+        }
+        position + END_OF_CENTRAL_DIR_SIZE + self.comment.len() as u64 - archive_start
+    }
+
+    fn write_archive<W: ArchiveWriter>(
+        &self,
+        destination: &mut W,
+        data_descriptors: bool,
+    ) -> Result<ArchiveStats, ZipError> {
+        let mut locations = Vec::new();
+        for (completed, file) in self.files.iter().enumerate() {
+            let data_descriptor = file.has_data_descriptor(data_descriptors);
+            destination.keep_together(file.local_header_size(data_descriptor))?;
+            let offset = destination.position();
+            locations.push((destination.volume(), offset));
+            file.to_bytes_filerecord(destination, offset, data_descriptor)?;
+            if let Some(ProgressCallback(progress)) = &self.progress {
+                progress(ProgressEvent {
+                    id: EntryId(file.sequence),
+                    filename: &file.filename,
+                    completed: completed + 1,
+                });
+            }
+        }
+        self.write_central_directory(destination, &locations, data_descriptors)
+    }
+
+    /// Serialize the central directory entries of `files`, whose local headers are at
+    /// `locations`. The entries only depend on those, so for large archives they are serialized
+    /// in parallel on the archive's thread pool. Sequential archives never use a pool.
+    fn serialize_direntries(
+        &self,
+        files: &[ZipFile],
+        locations: &[(u32, u64)],
+        data_descriptors: bool,
+    ) -> Result<Vec<Vec<u8>>, ZipError> {
+        let serialize = |(file, &(volume, offset)): (&ZipFile, &(u32, u64))| {
+            let mut direntry = Vec::with_capacity(file.direntry_size(offset) as usize);
+            file.to_bytes_direntry(
+                &mut direntry,
+                offset,
+                file.has_data_descriptor(data_descriptors),
+                self.version_made_by,
+                volume,
+            )?;
+            Ok(direntry)
+        };
+        if self.sequential || self.files.len() < DIRECTORY_CHUNK_ENTRIES {
+            return files.iter().zip(locations).map(serialize).collect();
+        }
+        let serialize_all = || files.par_iter().zip(locations).map(serialize).collect();
+        match self.thread_pool {
+            Some(thread_pool) => thread_pool.install(serialize_all),
+            None => serialize_all(),
+        }
+    }
+
+    /// Write the central directory and the end of central directory records. `locations` has the
+    /// volume and offset of the local header of every file.
+    fn write_central_directory<W: ArchiveWriter>(
+        &self,
+        destination: &mut W,
+        locations: &[(u32, u64)],
+        data_descriptors: bool,
+    ) -> Result<ArchiveStats, ZipError> {
+        let entry_count = self.existing_entries.len() + self.files.len();
+        // Where the central directory starts, the first entry can still move it to the next
+        // volume
+        let mut central_dir_start = None;
+        let mut central_dir_size = 0;
+        let mut last_volume_entries = 0;
+        for entry in &self.existing_entries {
+            central_dir_start.get_or_insert((destination.volume(), destination.position()));
+            destination.write_all(&entry.raw)?;
+            central_dir_size += entry.raw.len() as u64;
+            last_volume_entries += 1;
+        }
+        let chunks = self
+            .files
+            .chunks(DIRECTORY_CHUNK_ENTRIES)
+            .zip(locations.chunks(DIRECTORY_CHUNK_ENTRIES));
+        for (files, locations) in chunks {
+            for direntry in self.serialize_direntries(files, locations, data_descriptors)? {
+                let size = direntry.len() as u64;
+                let previous_volume = destination.volume();
+                destination.keep_together(size)?;
+                if destination.volume() != previous_volume {
+                    last_volume_entries = 0;
+                }
+                central_dir_start.get_or_insert((destination.volume(), destination.position()));
+                destination.write_all(&direntry)?;
+                central_dir_size += size;
+                last_volume_entries += 1;
+            }
+        }
+
+        let mut end_size = END_OF_CENTRAL_DIR_SIZE + self.comment.len() as u64;
+        let (mut central_dir_volume, mut central_dir_offset) =
+            central_dir_start.unwrap_or((destination.volume(), destination.position()));
+        let zip64 =
+            needs_zip64_end_of_central_dir(entry_count, central_dir_offset, central_dir_size);
+        if zip64 {
+            end_size += ZIP64_END_OF_CENTRAL_DIR_TOTAL_SIZE;
+        }
+        // The end of central directory records are always on the last volume
+        let previous_volume = destination.volume();
+        destination.keep_together(end_size)?;
+        if destination.volume() != previous_volume {
+            last_volume_entries = 0;
+        }
+        if central_dir_start.is_none() {
+            (central_dir_volume, central_dir_offset) =
+                (destination.volume(), destination.position());
+        }
+        let volume = destination.volume();
+
+        if zip64 {
+            let zip64_end_offset = destination.position();
+            // ZIP64 end of central directory record
+            destination.write_all(&ZIP64_END_OF_CENTRAL_DIR_SIGNATURE.to_le_bytes())?;
+            // Size of the record
+            destination.write_all(&ZIP64_END_OF_CENTRAL_DIR_SIZE.to_le_bytes())?;
+            // version made by
+            destination.write_all(&self.version_made_by.to_le_bytes())?;
+            // version needed to extract
+            destination.write_all(&ZIP64_VERSION_NEEDED_TO_EXTRACT.to_le_bytes())?;
+            // number of this disk
+            destination.write_all(&volume.to_le_bytes())?;
+            // number of the disk with start
+            destination.write_all(&central_dir_volume.to_le_bytes())?;
+            // Number of entries on this disk
+            destination.write_all(&(last_volume_entries as u64).to_le_bytes())?;
+            // Number of entries
+            destination.write_all(&(entry_count as u64).to_le_bytes())?;
+            // Central dir size
+            destination.write_all(&central_dir_size.to_le_bytes())?;
+            // Central dir offset
+            destination.write_all(&central_dir_offset.to_le_bytes())?;
+
+            // ZIP64 end of central directory locator
+            destination.write_all(&ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIGNATURE.to_le_bytes())?;
+            // number of the disk with the ZIP64 end of central directory
+            destination.write_all(&volume.to_le_bytes())?;
+            // ZIP64 end of central directory offset
+            destination.write_all(&zip64_end_offset.to_le_bytes())?;
+            // Total number of disks
+            destination.write_all(&(volume + 1).to_le_bytes())?;
+        }
+
+        // Signature
+        destination.write_all(&END_OF_CENTRAL_DIR_SIGNATURE.to_le_bytes())?;
+        // number of this disk
+        destination.write_all(&(volume as u16).to_le_bytes())?;
+        // number of the disk with start
+        destination.write_all(&(central_dir_volume as u16).to_le_bytes())?;
+        // Number of entries on this disk
+        destination.write_all(&zip64_u16(last_volume_entries).to_le_bytes())?;
+        // Number of entries
+        destination.write_all(&zip64_u16(entry_count).to_le_bytes())?;
+        // Central dir size
+        destination.write_all(&zip64_u32(central_dir_size).to_le_bytes())?;
+        // Central dir offset
+        destination.write_all(&zip64_u32(central_dir_offset).to_le_bytes())?;
+        // Comment length
+        destination.write_all(&(self.comment.len() as u16).to_le_bytes())?;
+        // Comment
+        destination.write_all(&self.comment)?;
+
+        Ok(ArchiveStats {
+            entries: entry_count,
+            bytes_written: destination.bytes_written(),
+            compressed_bytes: self
+                .existing_entries
+                .iter()
+                .map(|entry| entry.compressed_size)
+                .chain(self.files.iter().map(ZipFile::compressed_size))
+                .sum(),
+            uncompressed_bytes: self
+                .existing_entries
+                .iter()
+                .map(|entry| entry.uncompressed_size)
+                .chain(self.files.iter().map(|file| file.uncompressed_size))
+                .sum(),
+            central_dir_offset,
+            central_dir_size,
+            zip64_used: zip64
+                || self.existing_entries.iter().any(|entry| {
+                    [
+                        entry.compressed_size,
+                        entry.uncompressed_size,
+                        entry.local_header_offset,
+                    ]
+                    .iter()
+                    .any(|&value| value >= u32::MAX as u64)
+                })
+                || self
+                    .files
+                    .iter()
+                    .zip(locations)
+                    .any(|(file, &(_, offset))| file.uses_zip64(offset)),
+            end_offset: destination.position(),
+        })
+    }
+}
+
+impl ZipArchive<'static> {
+    /// Create an archive that compresses files on rayon's global thread pool
+    pub fn with_global_pool() -> Self {
+        Self::with_pool_and_level(None, DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Create an archive that doesn't use a thread pool. Files are compressed on the calling thread
+    /// by the `add_*` methods, which only return once they are done, so there is no overhead from
+    /// sending them between threads. Useful for small archives, where starting threads takes
+    /// longer than compressing. [`ZipArchive::set_parallel_deflate`] and [`BuiltArchive::verify`]
+    /// still use rayon's global thread pool.
+    pub fn new_sequential() -> Self {
+        let mut archive = Self::with_global_pool();
+        archive.sequential = true;
+        archive
+    }
+
+    /// Open an existing archive to add more files to it, compressing them on rayon's global
+    /// thread pool. See [`ZipArchive::append`].
+    pub fn append_with_global_pool<R: Read + Seek>(archive: &mut R) -> Result<Self, ZipError> {
+        Self::append_with_pool(None, archive)
+    }
+}
+
+impl Default for ZipArchive<'static> {
+    fn default() -> Self {
+        Self::with_global_pool()
+    }
+}
+
+/// Configuration of a [`ZipArchive`], as an alternative to setting everything on the archive
+/// itself. Settings that aren't given keep the defaults of [`ZipArchive::new`].
+#[derive(Debug, Clone, Default)]
+pub struct ZipArchiveBuilder<'a> {
+    thread_pool: Option<&'a ThreadPool>,
+    max_in_flight: Option<usize>,
+    compression_type: Option<CompressionType>,
+    level: Option<u32>,
+    auto_store: bool,
+    #[cfg(feature = "tempfile")]
+    use_temp_files: bool,
+    parallel_deflate: Option<usize>,
+    max_compression_threads: Option<usize>,
+    #[cfg(feature = "memmap2")]
+    memory_map: bool,
+    #[cfg(feature = "deflate-dictionary")]
+    deflate_dictionary: Option<Vec<u8>>,
+    extended_timestamps: bool,
+    ntfs_timestamps: bool,
+    preserve_ownership: bool,
+    detect_text: bool,
+    symlink_handling: SymlinkHandling,
+    allow_duplicates: bool,
+    reject_empty: bool,
+    name_sanitization: NameSanitization,
+    entry_order: EntryOrder,
+    version_made_by: Option<(HostSystem, u8)>,
+    dos_attributes: bool,
+    attribute_mode: AttributeMode,
+    fixed_timestamp: Option<SystemTime>,
+    timestamp_clamp: Option<SystemTime>,
+    reproducible: bool,
+    checksums_only: bool,
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    password: Option<String>,
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    encryption_method: EncryptionMethod,
+    comment: String,
+}
+
+impl<'a> ZipArchiveBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress files on `thread_pool`. Without a pool, rayon's global thread pool is used.
+    pub fn pool(mut self, thread_pool: &'a ThreadPool) -> Self {
+        self.thread_pool = Some(thread_pool);
+        self
+    }
+
+    /// See [`ZipArchive::with_capacity`]
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// See [`ZipArchive::set_compression_type`]
+    pub fn compression(mut self, compression_type: CompressionType) -> Self {
+        self.compression_type = Some(compression_type);
+        self
+    }
+
+    /// Compression level from 0 to 9. Unlike [`ZipArchive::set_compression_level`], levels above 9
+    /// make [`ZipArchiveBuilder::build`] fail instead of being clamped.
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// See [`ZipArchive::set_auto_store`]
+    pub fn auto_store(mut self, auto_store: bool) -> Self {
+        self.auto_store = auto_store;
+        self
+    }
+
+    /// See [`ZipArchive::set_use_temp_files`]
+    #[cfg(feature = "tempfile")]
+    pub fn use_temp_files(mut self, use_temp_files: bool) -> Self {
+        self.use_temp_files = use_temp_files;
+        self
+    }
+
+    /// See [`ZipArchive::set_parallel_deflate`]
+    pub fn parallel_deflate(mut self, chunk_size: usize) -> Self {
+        self.parallel_deflate = Some(chunk_size);
+        self
+    }
+
+    /// See [`ZipArchive::set_max_compression_threads`]
+    pub fn max_compression_threads(mut self, max_threads: usize) -> Self {
+        self.max_compression_threads = Some(max_threads);
+        self
+    }
+
+    /// See [`ZipArchive::set_memory_map`]
+    #[cfg(feature = "memmap2")]
+    pub fn memory_map(mut self, memory_map: bool) -> Self {
+        self.memory_map = memory_map;
+        self
+    }
+
+    /// See [`ZipArchive::set_deflate_dictionary`]
+    #[cfg(feature = "deflate-dictionary")]
+    pub fn deflate_dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.deflate_dictionary = Some(dictionary.to_vec());
+        self
+    }
+
+    /// See [`ZipArchive::set_extended_timestamps`]
+    pub fn extended_timestamps(mut self, extended_timestamps: bool) -> Self {
+        self.extended_timestamps = extended_timestamps;
+        self
+    }
+
+    /// See [`ZipArchive::set_ntfs_timestamps`]
+    pub fn ntfs_timestamps(mut self, ntfs_timestamps: bool) -> Self {
+        self.ntfs_timestamps = ntfs_timestamps;
+        self
+    }
+
+    /// See [`ZipArchive::set_preserve_ownership`]
+    pub fn preserve_ownership(mut self, preserve_ownership: bool) -> Self {
+        self.preserve_ownership = preserve_ownership;
+        self
+    }
+
+    /// See [`ZipArchive::set_detect_text`]
+    pub fn detect_text(mut self, detect_text: bool) -> Self {
+        self.detect_text = detect_text;
+        self
+    }
+
+    /// See [`ZipArchive::set_symlink_handling`]
+    pub fn symlink_handling(mut self, symlink_handling: SymlinkHandling) -> Self {
+        self.symlink_handling = symlink_handling;
+        self
+    }
+
+    /// See [`ZipArchive::set_allow_duplicates`]
+    pub fn allow_duplicates(mut self, allow_duplicates: bool) -> Self {
+        self.allow_duplicates = allow_duplicates;
+        self
+    }
+
+    /// See [`ZipArchive::set_reject_empty`]
+    pub fn reject_empty(mut self, reject_empty: bool) -> Self {
+        self.reject_empty = reject_empty;
+        self
+    }
+
+    /// See [`ZipArchive::set_name_sanitization`]
+    pub fn name_sanitization(mut self, name_sanitization: NameSanitization) -> Self {
+        self.name_sanitization = name_sanitization;
+        self
+    }
+
+    /// See [`ZipArchive::set_entry_order`]
+    pub fn entry_order(mut self, entry_order: EntryOrder) -> Self {
+        self.entry_order = entry_order;
+        self
+    }
+
+    /// See [`ZipArchive::set_version_made_by`]
+    pub fn version_made_by(mut self, host_system: HostSystem, spec_version: u8) -> Self {
+        self.version_made_by = Some((host_system, spec_version));
+        self
+    }
+
+    /// See [`ZipArchive::set_dos_attributes`]
+    pub fn dos_attributes(mut self, dos_attributes: bool) -> Self {
+        self.dos_attributes = dos_attributes;
+        self
+    }
+
+    /// See [`ZipArchive::set_external_attributes_mode`]
+    pub fn external_attributes_mode(mut self, attribute_mode: AttributeMode) -> Self {
+        self.attribute_mode = attribute_mode;
+        self
+    }
+
+    /// See [`ZipArchive::set_fixed_timestamp`]
+    pub fn fixed_timestamp(mut self, timestamp: SystemTime) -> Self {
+        self.fixed_timestamp = Some(timestamp);
+        self
+    }
+
+    /// See [`ZipArchive::set_timestamp_clamp`]
+    pub fn timestamp_clamp(mut self, clamp: SystemTime) -> Self {
+        self.timestamp_clamp = Some(clamp);
+        self
+    }
+
+    /// See [`ZipArchive::set_reproducible`]
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// See [`ZipArchive::set_checksums_only`]
+    pub fn checksums_only(mut self, checksums_only: bool) -> Self {
+        self.checksums_only = checksums_only;
+        self
+    }
+
+    /// See [`ZipArchive::set_password`]
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// See [`ZipArchive::set_encryption_method`]
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    pub fn encryption_method(mut self, encryption_method: EncryptionMethod) -> Self {
+        self.encryption_method = encryption_method;
+        self
+    }
+
+    /// See [`ZipArchive::set_comment`]
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = comment.into();
+        self
+    }
+
+    /// Create the archive. Fails with [`ZipError::InvalidCompressionLevel`] if the level is above
+    /// 9 and with [`ZipError::CommentTooLong`] if the comment doesn't fit.
+    pub fn build(self) -> Result<ZipArchive<'a>, ZipError> {
+        let level = self.level.unwrap_or(DEFAULT_COMPRESSION_LEVEL);
+        if level > MAX_COMPRESSION_LEVEL {
+            return Err(ZipError::InvalidCompressionLevel(level));
+        }
+        let mut archive = match self.max_in_flight {
+            Some(max_in_flight) => {
+                let (tx, rx) = sync_channel(max_in_flight);
+                ZipArchive::with_channel(self.thread_pool, level, EntrySender::Bounded(tx), rx)
+            }
+            None => ZipArchive::with_pool_and_level(self.thread_pool, level),
+        };
+        archive.set_comment(&self.comment)?;
+        if let Some(compression_type) = self.compression_type {
+            archive.set_compression_type(compression_type);
+        }
+        archive.set_auto_store(self.auto_store);
+        #[cfg(feature = "tempfile")]
+        archive.set_use_temp_files(self.use_temp_files);
+        archive.set_parallel_deflate(self.parallel_deflate);
+        archive.set_max_compression_threads(self.max_compression_threads);
+        #[cfg(feature = "memmap2")]
+        archive.set_memory_map(self.memory_map);
+        #[cfg(feature = "deflate-dictionary")]
+        archive.set_deflate_dictionary(self.deflate_dictionary.as_deref());
+        archive.set_extended_timestamps(self.extended_timestamps);
+        archive.set_ntfs_timestamps(self.ntfs_timestamps);
+        archive.set_preserve_ownership(self.preserve_ownership);
+        archive.set_detect_text(self.detect_text);
+        archive.set_symlink_handling(self.symlink_handling);
+        archive.set_allow_duplicates(self.allow_duplicates);
+        archive.set_reject_empty(self.reject_empty);
+        archive.set_name_sanitization(self.name_sanitization);
+        archive.set_entry_order(self.entry_order);
+        if let Some((host_system, spec_version)) = self.version_made_by {
+            archive.set_version_made_by(host_system, spec_version);
+        }
+        archive.set_dos_attributes(self.dos_attributes);
+        archive.set_external_attributes_mode(self.attribute_mode);
+        archive.set_fixed_timestamp(self.fixed_timestamp);
+        archive.set_timestamp_clamp(self.timestamp_clamp);
+        archive.set_reproducible(self.reproducible);
+        archive.set_checksums_only(self.checksums_only);
+        #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+        archive.set_password(self.password.as_deref());
+        #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+        archive.set_encryption_method(self.encryption_method);
+        Ok(archive)
+    }
+}
+
+#[derive(Debug)]
+struct ZipFile {
+    compression_type: CompressionType,
+    crc: u32,
+    uncompressed_size: u64,
+    /// Packed MS-DOS date and time of last modification, in that order
+    mtime: (u16, u16),
+    extended_timestamp: Option<ExtendedTimestamp>,
+    ntfs_timestamp: Option<NtfsTimestamp>,
+    filename: String,
+    /// Only stored in the central directory
+    comment: String,
+    data: ZipFileData,
+    external_file_attributes: u32,
+    /// Whether the internal file attributes mark the entry as text
+    text: bool,
+    encryption: Option<EncryptionMethod>,
+    owner: Option<UnixOwner>,
+    /// Level the data was compressed at, unknown for precompressed data
+    compression_level: Option<u32>,
+    /// Whether the data was deflated with a preset dictionary, which isn't stored in the archive
+    preset_dictionary: bool,
+    /// Position of the entry in the order entries were added, set when it's queued
+    sequence: usize,
+}
+
+/// Sending half of the channel that compressed entries are sent through
+#[derive(Debug, Clone)]
+enum EntrySender {
+    Unbounded(Sender<Result<ZipFile, ZipError>>),
+    Bounded(SyncSender<Result<ZipFile, ZipError>>),
+}
+
+impl EntrySender {
+    /// The receiver only goes away if the archive was dropped without being written, in which
+    /// case nobody is interested in the entry anymore, so it's dropped as well
+    fn send(&self, entry: Result<ZipFile, ZipError>) {
+        let _ = match self {
+            Self::Unbounded(tx) => tx.send(entry),
+            Self::Bounded(tx) => tx.send(entry),
+        };
+    }
+}
+
+/// Unix timestamps of an entry, stored in the Info-ZIP extended timestamp extra field
+#[derive(Debug, Clone, Copy)]
+struct ExtendedTimestamp {
+    modified: Option<i32>,
+    accessed: Option<i32>,
+    created: Option<i32>,
+}
+
+impl ExtendedTimestamp {
+    fn modified(time: SystemTime) -> Self {
+        Self {
+            modified: Some(unix_timestamp(time)),
+            accessed: None,
+            created: None,
+        }
+    }
+
+    /// Flags telling which timestamps are present in the local header's field
+    fn flags(&self) -> u8 {
+        u8::from(self.modified.is_some())
+            | u8::from(self.accessed.is_some()) << 1
+            | u8::from(self.created.is_some()) << 2
+    }
+
+    /// Write the field for the local header, which has all of the timestamps
+    fn write_local(&self, buf: &mut Vec<u8>) {
+        let times: Vec<i32> = [self.modified, self.accessed, self.created]
+            .into_iter()
+            .flatten()
+            .collect();
+        buf.extend_from_slice(&EXTENDED_TIMESTAMP_EXTRA_FIELD_ID.to_le_bytes());
+        buf.extend_from_slice(&(1 + times.len() as u16 * 4).to_le_bytes());
+        buf.push(self.flags());
+        for time in times {
+            buf.extend_from_slice(&time.to_le_bytes());
+        }
+    }
+
+    /// Write the field for the central directory entry, which only has the modification time.
+    /// The flags are the same as in the local header.
+    fn write_central(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&EXTENDED_TIMESTAMP_EXTRA_FIELD_ID.to_le_bytes());
+        buf.extend_from_slice(&(1 + u16::from(self.modified.is_some()) * 4).to_le_bytes());
+        buf.push(self.flags());
+        if let Some(modified) = self.modified {
+            buf.extend_from_slice(&modified.to_le_bytes());
+        }
+    }
+}
+
+/// Modification, access and creation times of an entry as Windows FILETIMEs, stored in the NTFS
+/// extra field
+#[derive(Debug, Clone, Copy)]
+struct NtfsTimestamp {
+    modified: u64,
+    accessed: u64,
+    created: u64,
+}
+
+impl NtfsTimestamp {
+    /// The field is the same in the local header and the central directory
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&NTFS_EXTRA_FIELD_ID.to_le_bytes());
+        buf.extend_from_slice(&32_u16.to_le_bytes());
+        // reserved
+        buf.extend_from_slice(&0_u32.to_le_bytes());
+        // attribute tag and size of the timestamps attribute
+        buf.extend_from_slice(&1_u16.to_le_bytes());
+        buf.extend_from_slice(&24_u16.to_le_bytes());
+        buf.extend_from_slice(&self.modified.to_le_bytes());
+        buf.extend_from_slice(&self.accessed.to_le_bytes());
+        buf.extend_from_slice(&self.created.to_le_bytes());
+    }
+}
+
+/// UID and GID of the owner of an entry, stored in the Info-ZIP new Unix extra field
 #[derive(Debug, Clone, Copy)]
-pub enum CompressionType {
-    Stored = 0,
-    Deflate = 8,
+struct UnixOwner {
+    uid: u32,
+    gid: u32,
+}
+
+impl UnixOwner {
+    /// The field is the same in the local header and the central directory
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&UNIX_OWNER_EXTRA_FIELD_ID.to_le_bytes());
+        buf.extend_from_slice(&11_u16.to_le_bytes());
+        // version
+        buf.push(1);
+        buf.push(4);
+        buf.extend_from_slice(&self.uid.to_le_bytes());
+        buf.push(4);
+        buf.extend_from_slice(&self.gid.to_le_bytes());
+    }
 }
 
+/// Compressed data of an entry
 #[derive(Debug)]
-pub struct ZipArchive<'a> {
-    thread_pool: &'a ThreadPool,
-    tx: Sender<ZipFile>,
-    rx: Receiver<ZipFile>,
+enum ZipFileData {
+    Memory(Vec<u8>),
+    /// Data in an anonymous temporary file, with its length
+    #[cfg(feature = "tempfile")]
+    TempFile(File, u64),
+    /// Data that was already written to the destination, only its length is kept
+    Written(u64),
 }
 
-impl<'a> ZipArchive<'a> {
-    pub fn new(thread_pool: &'a ThreadPool) -> Self {
-        let (tx, rx) = channel();
-        Self {
-            thread_pool,
-            tx,
-            rx,
+impl ZipFileData {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Memory(data) => data.len() as u64,
+            #[cfg(feature = "tempfile")]
+            Self::TempFile(_, len) => *len,
+            Self::Written(len) => *len,
         }
     }
 
-    fn fs_file_to_archive_file(fs_path: &Path, archived_name: &str) -> ZipFile {
-        let file = File::open(fs_path).unwrap();
-        let uncompressed_size = file.metadata().unwrap().len() as u32;
-        let crc_reader = CrcReader::new(file);
-        let mut encoder = DeflateEncoder::new(crc_reader, Compression::new(9));
-        let mut data = Vec::new();
-        encoder.read_to_end(&mut data).unwrap();
-        let crc_reader = encoder.into_inner();
-        let crc = crc_reader.crc().sum();
-        ZipFile {
-            compression_type: CompressionType::Deflate,
-            crc,
-            uncompressed_size,
-            filename: archived_name.into(),
-            data,
-            external_file_attributes: 0o100644 << 16, // Possible improvement: read
-                                                      // permissions/attributes from fs
+    /// Run the data through `transform` into new data that is kept the same way and is `added`
+    /// bytes longer
+    #[cfg(any(feature = "aes", feature = "zipcrypto"))]
+    fn transform<F>(&self, added: usize, transform: F) -> Result<Self, std::io::Error>
+    where
+        F: FnOnce(&mut dyn Read, &mut dyn Write) -> Result<(), std::io::Error>,
+    {
+        let mut reader = self.reader()?;
+        match self {
+            Self::Memory(data) => {
+                let mut transformed = Vec::with_capacity(data.len() + added);
+                transform(&mut reader, &mut transformed)?;
+                Ok(Self::Memory(transformed))
+            }
+            #[cfg(feature = "tempfile")]
+            Self::TempFile(_, len) => {
+                let mut temp_file = tempfile::tempfile()?;
+                transform(&mut reader, &mut temp_file)?;
+                Ok(Self::TempFile(temp_file, len + added as u64))
+            }
+            Self::Written(_) => Err(already_written()),
         }
     }
 
-    fn slice_to_archive_file(slice: &[u8], archived_name: &str) -> ZipFile {
-        let uncompressed_size = slice.len() as u32;
-        let crc_reader = CrcReader::new(slice);
-        let mut encoder = DeflateEncoder::new(crc_reader, Compression::new(9));
-        let mut data = Vec::new();
-        encoder.read_to_end(&mut data).unwrap();
-        let crc_reader = encoder.into_inner();
-        let crc = crc_reader.crc().sum();
-        ZipFile {
-            compression_type: CompressionType::Deflate,
-            crc,
-            uncompressed_size,
-            filename: archived_name.into(),
-            data,
-            external_file_attributes: 0o100644 << 16,
+    /// Read the compressed data from the start
+    fn reader(&self) -> Result<Box<dyn Read + Send + '_>, std::io::Error> {
+        match self {
+            Self::Memory(data) => Ok(Box::new(&data[..])),
+            #[cfg(feature = "tempfile")]
+            Self::TempFile(file, len) => {
+                let mut file = file;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(file.take(*len)))
+            }
+            Self::Written(_) => Err(already_written()),
         }
     }
 
-    pub fn add_file_from_fs(&mut self, fs_path: &Path, archived_name: &str) {
-        let thread_tx = self.tx.clone();
-        let fs_path = fs_path.to_path_buf();
-        let archived_name = archived_name.to_string();
-        self.thread_pool.spawn(move || {
-            thread_tx
-                .send(Self::fs_file_to_archive_file(&fs_path, &archived_name))
-                .unwrap()
-        })
+    fn write_to<W: Write>(&self, buf: &mut W) -> Result<(), std::io::Error> {
+        match self {
+            Self::Memory(data) => buf.write_all(data),
+            #[cfg(feature = "tempfile")]
+            Self::TempFile(file, _) => {
+                let mut file = file;
+                file.seek(SeekFrom::Start(0))?;
+                std::io::copy(&mut file, buf)?;
+                Ok(())
+            }
+            Self::Written(_) => Err(already_written()),
+        }
     }
+}
 
-    pub fn add_file_from_slice(&mut self, slice: &[u8], archived_name: &str) {
-        let thread_tx = self.tx.clone();
-        let slice = slice.to_vec();
-        let archived_name = archived_name.to_string();
-        self.thread_pool.spawn(move || {
-            thread_tx
-                .send(Self::slice_to_archive_file(&slice, &archived_name))
-                .unwrap()
-        })
-    }
+fn already_written() -> std::io::Error {
+    std::io::Error::other("entry data was already written")
+}
 
-    pub fn add_directory(&mut self, archived_name: &str) {
-        let compressed_file = ZipFile::directory(archived_name.into());
-        self.tx.send(compressed_file).unwrap();
+impl ZipFile {
+    /// Minimum version of the format an extractor has to support to extract this entry. ZIP64 is
+    /// needed if the sizes or the offset of the local header don't fit in 32 bits.
+    fn version_needed(&self, local_header_offset: u64) -> u16 {
+        let method_version = match self.compression_type {
+            #[cfg(feature = "bzip2")]
+            CompressionType::Bzip2 => BZIP2_VERSION_NEEDED_TO_EXTRACT,
+            #[cfg(feature = "zstd")]
+            CompressionType::Zstd => ZSTD_VERSION_NEEDED_TO_EXTRACT,
+            _ => VERSION_NEEDED_TO_EXTRACT,
+        };
+        #[cfg(feature = "aes")]
+        let method_version = if self.encryption == Some(EncryptionMethod::Aes256) {
+            method_version.max(aes::AES_VERSION_NEEDED_TO_EXTRACT)
+        } else {
+            method_version
+        };
+        if self.uses_zip64(local_header_offset) {
+            method_version.max(ZIP64_VERSION_NEEDED_TO_EXTRACT)
+        } else {
+            method_version
+        }
     }
 
-    pub fn write<W: Write + Seek>(self, destination: &mut W) -> Result<(), std::io::Error> {
-        let Self {
-            thread_pool: _,
-            tx,
-            rx,
-        } = self;
-        drop(tx);
+    /// Whether the entry is followed by a data descriptor when the archive is written with
+    /// `data_descriptors`. ZipCrypto's password check uses the modification time instead of the
+    /// CRC if there is one, but the encryption header was made for the CRC.
+    fn has_data_descriptor(&self, data_descriptors: bool) -> bool {
+        match self.encryption {
+            #[cfg(feature = "zipcrypto")]
+            Some(EncryptionMethod::ZipCrypto) => false,
+            _ => data_descriptors,
+        }
+    }
 
-        let files: Vec<ZipFile> = rx.iter().collect();
+    /// General purpose bit flags, shared by the local file header and the central directory entry
+    fn flags(&self, data_descriptor: bool) -> u16 {
+        let mut flags = 0;
+        if self.encryption.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        // Like Info-ZIP, levels 8 and 9 are marked as maximum, level 2 as fast, level 1 as super
+        // fast with both bits and any other as normal, see APPNOTE 4.4.4
+        if self.compression_type == CompressionType::Deflate {
+            match self.compression_level {
+                Some(8 | 9) => flags |= FLAG_DEFLATE_MAXIMUM,
+                Some(2) => flags |= FLAG_DEFLATE_FAST,
+                Some(1) => flags |= FLAG_DEFLATE_MAXIMUM | FLAG_DEFLATE_FAST,
+                _ => {}
+            }
+        }
+        if data_descriptor {
+            flags |= FLAG_DATA_DESCRIPTOR;
+        }
+        if !self.filename.is_ascii() {
+            flags |= FLAG_UTF8_NAME;
+        }
+        flags
+    }
 
-        let mut offsets = Vec::new();
-        for file in &files {
-            offsets.push(destination.stream_position().unwrap() as u32);
-            file.to_bytes_filerecord(destination);
+    /// Compression method stored in the headers
+    fn compression_method(&self) -> u16 {
+        #[cfg(feature = "aes")]
+        if self.encryption == Some(EncryptionMethod::Aes256) {
+            return aes::AES_COMPRESSION_METHOD;
         }
-        let central_dir_offset = destination.stream_position()? as u32;
-        for (file, offset) in files.iter().zip(offsets.into_iter()) {
-            file.to_bytes_direntry(destination, offset)
+        self.compression_type as u16
+    }
+
+    /// CRC stored in the headers. AE-2 leaves it out of encrypted entries, so that it can't be
+    /// used to check guesses of small files' contents.
+    fn header_crc(&self) -> u32 {
+        match self.encryption {
+            #[cfg(feature = "aes")]
+            Some(EncryptionMethod::Aes256) => 0,
+            _ => self.crc,
         }
-        let central_dir_start = destination.stream_position()? as u32;
+    }
 
-        // Signature
-        destination
-            .write_all(&END_OF_CENTRAL_DIR_SIGNATURE.to_le_bytes())
-            .unwrap();
-        // number of this disk
-        destination.write_all(&0_u16.to_le_bytes()).unwrap();
-        // number of the disk with start
-        destination.write_all(&0_u16.to_le_bytes()).unwrap();
-        // Number of entries on this disk
-        destination
-            .write_all(&(files.len() as u16).to_le_bytes())
-            .unwrap();
-        // Number of entries
-        destination
-            .write_all(&(files.len() as u16).to_le_bytes())
-            .unwrap();
-        // Central dir size
-        destination
-            .write_all(&(central_dir_start - central_dir_offset).to_le_bytes())
-            .unwrap();
-        // Central dir offset
-        destination
-            .write_all(&central_dir_offset.to_le_bytes())
-            .unwrap();
-        // Comment length
-        destination.write_all(&0_u16.to_le_bytes()).unwrap();
+    fn compressed_size(&self) -> u64 {
+        self.data.len()
+    }
 
+    /// Decompress the data and compare it to the recorded CRC and size. Encrypted data and data
+    /// deflated with a preset dictionary are not checked.
+    fn verify(&self) -> Result<(), ZipError> {
+        if self.encryption.is_some() || self.preset_dictionary {
+            return Ok(());
+        }
+        let corrupt = || ZipError::CorruptEntry(self.filename.clone());
+        let decoder = decoder(self.compression_type, self.data.reader()?)?;
+        let mut reader = CrcReader::new(CountingReader::new(decoder));
+        match std::io::copy(&mut reader, &mut std::io::sink()) {
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => {
+                return Err(corrupt())
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if reader.crc().sum() != self.crc || reader.get_ref().count != self.uncompressed_size {
+            return Err(corrupt());
+        }
         Ok(())
     }
-}
 
-#[derive(Debug)]
-struct ZipFile {
-    compression_type: CompressionType,
-    crc: u32,
-    uncompressed_size: u32,
-    filename: String,
-    data: Vec<u8>,
-    external_file_attributes: u32,
-}
+    fn info(&self) -> EntryInfo {
+        EntryInfo {
+            id: Some(EntryId(self.sequence)),
+            name: self.filename.clone(),
+            compression_method: self.compression_type as u16,
+            crc: self.crc,
+            compressed_size: self.compressed_size(),
+            uncompressed_size: self.uncompressed_size,
+        }
+    }
 
-impl ZipFile {
-    fn to_bytes_filerecord<W: Write + Seek>(&self, buf: &mut W) {
+    /// Set MS-DOS attributes matching the Unix permissions, unless there already are some
+    fn add_dos_attributes(&mut self) {
+        if self.external_file_attributes & 0xFFFF != 0 {
+            return;
+        }
+        let mode = self.external_file_attributes >> 16;
+        let mut attributes = if self.filename.ends_with('/') {
+            DOS_DIRECTORY
+        } else {
+            DOS_ARCHIVE
+        };
+        if mode != 0 && mode & 0o200 == 0 {
+            attributes |= DOS_READ_ONLY;
+        }
+        self.external_file_attributes |= attributes;
+    }
+
+    /// Change the external file attributes to the way `attribute_mode` writes them
+    fn apply_attribute_mode(&mut self, attribute_mode: AttributeMode, dos_attributes: bool) {
+        match attribute_mode {
+            AttributeMode::Unix => {
+                if dos_attributes {
+                    self.add_dos_attributes();
+                }
+            }
+            AttributeMode::Dos => {
+                self.add_dos_attributes();
+                self.external_file_attributes &= 0xFFFF;
+            }
+            AttributeMode::Zero => self.external_file_attributes = 0,
+        }
+    }
+
+    /// Whether the sizes don't fit in the regular header fields and have to be stored in a ZIP64
+    /// extra field instead
+    fn has_zip64_sizes(&self) -> bool {
+        self.uncompressed_size >= u32::MAX as u64 || self.compressed_size() >= u32::MAX as u64
+    }
+
+    /// Whether the entry has a ZIP64 extra field when its local header is at `local_header_offset`
+    fn uses_zip64(&self, local_header_offset: u64) -> bool {
+        self.has_zip64_sizes() || local_header_offset >= u32::MAX as u64
+    }
+
+    /// Extra field of the local file header. The ZIP64 field of a local header has to contain both
+    /// sizes, which are zero if they come in a data descriptor instead.
+    fn local_extra_field(&self, data_descriptor: bool) -> Vec<u8> {
+        let mut extra_field = Vec::new();
+        if self.has_zip64_sizes() {
+            let sizes = if data_descriptor {
+                [0, 0]
+            } else {
+                [self.uncompressed_size, self.compressed_size()]
+            };
+            write_zip64_extra_field(&mut extra_field, &sizes);
+        }
+        if let Some(extended_timestamp) = &self.extended_timestamp {
+            extended_timestamp.write_local(&mut extra_field);
+        }
+        if let Some(ntfs_timestamp) = &self.ntfs_timestamp {
+            ntfs_timestamp.write(&mut extra_field);
+        }
+        if let Some(owner) = &self.owner {
+            owner.write(&mut extra_field);
+        }
+        #[cfg(feature = "aes")]
+        if self.encryption == Some(EncryptionMethod::Aes256) {
+            aes::write_extra_field(&mut extra_field, self.compression_type);
+        }
+        extra_field
+    }
+
+    /// Extra field of the central directory entry. The ZIP64 field of a central directory entry
+    /// only contains the values that don't fit in their regular fields.
+    fn central_extra_field(&self, local_header_offset: u64) -> Vec<u8> {
+        let mut extra_field = Vec::new();
+        let zip64_values: Vec<u64> = [
+            self.uncompressed_size,
+            self.compressed_size(),
+            local_header_offset,
+        ]
+        .into_iter()
+        .filter(|value| *value >= u32::MAX as u64)
+        .collect();
+        if !zip64_values.is_empty() {
+            write_zip64_extra_field(&mut extra_field, &zip64_values);
+        }
+        if let Some(extended_timestamp) = &self.extended_timestamp {
+            extended_timestamp.write_central(&mut extra_field);
+        }
+        if let Some(ntfs_timestamp) = &self.ntfs_timestamp {
+            ntfs_timestamp.write(&mut extra_field);
+        }
+        if let Some(owner) = &self.owner {
+            owner.write(&mut extra_field);
+        }
+        #[cfg(feature = "aes")]
+        if self.encryption == Some(EncryptionMethod::Aes256) {
+            aes::write_extra_field(&mut extra_field, self.compression_type);
+        }
+        extra_field
+    }
+
+    /// Write the local file header followed by the data. With `data_descriptor` the CRC and sizes
+    /// are written in a data descriptor after the data instead of the header.
+    fn to_bytes_filerecord<W: Write>(
+        &self,
+        buf: &mut W,
+        local_header_offset: u64,
+        data_descriptor: bool,
+    ) -> Result<(), ZipError> {
+        let extra_field = self.local_extra_field(data_descriptor);
+        let crc = if data_descriptor {
+            0
+        } else {
+            self.header_crc()
+        };
+        let (compressed_size, uncompressed_size) = if self.has_zip64_sizes() {
+            (u32::MAX, u32::MAX)
+        } else if data_descriptor {
+            (0, 0)
+        } else {
+            (
+                zip64_u32(self.compressed_size()),
+                zip64_u32(self.uncompressed_size),
+            )
+        };
         // signature
-        buf.write_all(&FILE_RECORD_SIGNATURE.to_le_bytes()).unwrap();
+        buf.write_all(&FILE_RECORD_SIGNATURE.to_le_bytes())?;
         // version needed to extract
-        buf.write_all(&VERSION_NEEDED_TO_EXTRACT.to_le_bytes())
-            .unwrap();
+        buf.write_all(&self.version_needed(local_header_offset).to_le_bytes())?;
         // flags
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&self.flags(data_descriptor).to_le_bytes())?;
         // compression type
-        buf.write_all(&(self.compression_type as u16).to_le_bytes())
-            .unwrap();
-        // Time // TODO
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
-        // Date // TODO
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&self.compression_method().to_le_bytes())?;
+        // Time
+        buf.write_all(&self.mtime.1.to_le_bytes())?;
+        // Date
+        buf.write_all(&self.mtime.0.to_le_bytes())?;
         // crc
-        buf.write_all(&self.crc.to_le_bytes()).unwrap();
+        buf.write_all(&crc.to_le_bytes())?;
         // Compressed size
-        buf.write_all(&(self.data.len() as u32).to_le_bytes())
-            .unwrap();
+        buf.write_all(&compressed_size.to_le_bytes())?;
         // Uncompressed size
-        buf.write_all(&self.uncompressed_size.to_le_bytes())
-            .unwrap();
+        buf.write_all(&uncompressed_size.to_le_bytes())?;
         // Filename size
-        buf.write_all(&(self.filename.len() as u16).to_le_bytes())
-            .unwrap();
+        buf.write_all(&(self.filename.len() as u16).to_le_bytes())?;
         // extra field size
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&(extra_field.len() as u16).to_le_bytes())?;
         // Filename
-        buf.write_all(self.filename.as_bytes()).unwrap();
+        buf.write_all(self.filename.as_bytes())?;
+        // Extra field
+        buf.write_all(&extra_field)?;
         // Data
-        buf.write_all(&self.data).unwrap();
+        self.data.write_to(buf)?;
+        if data_descriptor {
+            self.to_bytes_data_descriptor(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Number of bytes written by [`ZipFile::to_bytes_filerecord`], including the data descriptor
+    fn filerecord_size(&self, data_descriptor: bool) -> u64 {
+        let descriptor_size = match (data_descriptor, self.has_zip64_sizes()) {
+            (false, _) => 0,
+            (true, false) => 16,
+            (true, true) => 24,
+        };
+        self.local_header_size(data_descriptor) + self.compressed_size() + descriptor_size
+    }
+
+    /// Size of the local file header, without the data that follows it
+    fn local_header_size(&self, data_descriptor: bool) -> u64 {
+        FILE_RECORD_SIZE
+            + self.filename.len() as u64
+            + self.local_extra_field(data_descriptor).len() as u64
+    }
+
+    /// Number of bytes written by [`ZipFile::to_bytes_direntry`]
+    fn direntry_size(&self, local_header_offset: u64) -> u64 {
+        DIRECTORY_ENTRY_SIZE
+            + self.filename.len() as u64
+            + self.central_extra_field(local_header_offset).len() as u64
+            + self.comment.len() as u64
+    }
+
+    /// Sizes in the data descriptor are 8 bytes wide if the local header has a ZIP64 extra field
+    fn to_bytes_data_descriptor<W: Write>(&self, buf: &mut W) -> Result<(), ZipError> {
+        // signature
+        buf.write_all(&DATA_DESCRIPTOR_SIGNATURE.to_le_bytes())?;
+        // crc
+        buf.write_all(&self.header_crc().to_le_bytes())?;
+        if self.has_zip64_sizes() {
+            // Compressed size
+            buf.write_all(&self.compressed_size().to_le_bytes())?;
+            // Uncompressed size
+            buf.write_all(&self.uncompressed_size.to_le_bytes())?;
+        } else {
+            // Compressed size
+            buf.write_all(&zip64_u32(self.compressed_size()).to_le_bytes())?;
+            // Uncompressed size
+            buf.write_all(&zip64_u32(self.uncompressed_size).to_le_bytes())?;
+        }
+        Ok(())
     }
 
-    fn to_bytes_direntry<W: Write + Seek>(&self, buf: &mut W, local_header_offset: u32) {
+    fn to_bytes_direntry<W: Write>(
+        &self,
+        buf: &mut W,
+        local_header_offset: u64,
+        data_descriptor: bool,
+        version_made_by: u16,
+        volume: u32,
+    ) -> Result<(), ZipError> {
+        if self.comment.len() > u16::MAX as usize {
+            return Err(ZipError::CommentTooLong);
+        }
+        let extra_field = self.central_extra_field(local_header_offset);
         // signature
-        buf.write_all(&DIRECTORY_ENTRY_SIGNATURE.to_le_bytes())
-            .unwrap();
+        buf.write_all(&DIRECTORY_ENTRY_SIGNATURE.to_le_bytes())?;
         // version made by
-        buf.write_all(&VERSION_MADE_BY.to_le_bytes()).unwrap();
+        buf.write_all(&version_made_by.to_le_bytes())?;
         // version needed to extract
-        buf.write_all(&VERSION_NEEDED_TO_EXTRACT.to_le_bytes())
-            .unwrap();
+        buf.write_all(&self.version_needed(local_header_offset).to_le_bytes())?;
         // flags
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&self.flags(data_descriptor).to_le_bytes())?;
         // compression type
-        buf.write_all(&(self.compression_type as u16).to_le_bytes())
-            .unwrap();
-        // Time // TODO
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
-        // Date // TODO
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&self.compression_method().to_le_bytes())?;
+        // Time
+        buf.write_all(&self.mtime.1.to_le_bytes())?;
+        // Date
+        buf.write_all(&self.mtime.0.to_le_bytes())?;
         // crc
-        buf.write_all(&self.crc.to_le_bytes()).unwrap();
+        buf.write_all(&self.header_crc().to_le_bytes())?;
         // Compressed size
-        buf.write_all(&(self.data.len() as u32).to_le_bytes())
-            .unwrap();
+        buf.write_all(&zip64_u32(self.compressed_size()).to_le_bytes())?;
         // Uncompressed size
-        buf.write_all(&self.uncompressed_size.to_le_bytes())
-            .unwrap();
+        buf.write_all(&zip64_u32(self.uncompressed_size).to_le_bytes())?;
         // Filename size
-        buf.write_all(&(self.filename.len() as u16).to_le_bytes())
-            .unwrap();
+        buf.write_all(&(self.filename.len() as u16).to_le_bytes())?;
         // extra field size
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&(extra_field.len() as u16).to_le_bytes())?;
         // comment size
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&(self.comment.len() as u16).to_le_bytes())?;
         // disk number start
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        buf.write_all(&(volume as u16).to_le_bytes())?;
         // internal file attributes
-        buf.write_all(&0_u16.to_le_bytes()).unwrap();
+        let internal_file_attributes = if self.text {
+            INTERNAL_ATTRIBUTE_TEXT
+        } else {
+            0
+        };
+        buf.write_all(&internal_file_attributes.to_le_bytes())?;
         // external file attributes
-        buf.write_all(&self.external_file_attributes.to_le_bytes())
-            .unwrap();
+        buf.write_all(&self.external_file_attributes.to_le_bytes())?;
         // relative offset of local header
-        buf.write_all(&local_header_offset.to_le_bytes()).unwrap();
+        buf.write_all(&zip64_u32(local_header_offset).to_le_bytes())?;
         // Filename
-        buf.write_all(self.filename.as_bytes()).unwrap();
+        buf.write_all(self.filename.as_bytes())?;
+        // Extra field
+        buf.write_all(&extra_field)?;
+        // Comment
+        buf.write_all(self.comment.as_bytes())?;
+        Ok(())
     }
 
-    fn directory(mut name: String) -> Self {
-        name = name.replace('\\', "/");
-        if !(name.ends_with('/') || name.ends_with('\\')) {
+    fn directory(mut name: String, modified: SystemTime, extended_timestamp: bool) -> Self {
+        if !name.ends_with('/') {
             name += "/"
         };
         Self {
             compression_type: CompressionType::Stored,
             crc: 0,
             uncompressed_size: 0,
+            mtime: dos_date_time(modified),
+            extended_timestamp: extended_timestamp.then(|| ExtendedTimestamp::modified(modified)),
+            ntfs_timestamp: None,
+            filename: name,
+            comment: String::new(),
+            data: ZipFileData::Memory(Vec::new()),
+            external_file_attributes: DIRECTORY_ATTRIBUTES,
+            text: false,
+            encryption: None,
+            owner: None,
+            compression_level: None,
+            preset_dictionary: false,
+            sequence: 0,
+        }
+    }
+
+    /// Symlink entries are stored, with the target path as their contents
+    fn symlink(name: String, target: &str, modified: SystemTime, extended_timestamp: bool) -> Self {
+        let mut crc = Crc::new();
+        crc.update(target.as_bytes());
+        Self {
+            compression_type: CompressionType::Stored,
+            crc: crc.sum(),
+            uncompressed_size: target.len() as u64,
+            mtime: dos_date_time(modified),
+            extended_timestamp: extended_timestamp.then(|| ExtendedTimestamp::modified(modified)),
+            ntfs_timestamp: None,
+            filename: name,
+            comment: String::new(),
+            data: ZipFileData::Memory(target.as_bytes().to_vec()),
+            external_file_attributes: SYMLINK_ATTRIBUTES,
+            text: false,
+            encryption: None,
+            owner: None,
+            compression_level: None,
+            preset_dictionary: false,
+            sequence: 0,
+        }
+    }
+
+    fn empty_file(name: String, modified: SystemTime, extended_timestamp: bool) -> Self {
+        Self {
+            compression_type: CompressionType::Stored,
+            crc: 0,
+            uncompressed_size: 0,
+            mtime: dos_date_time(modified),
+            extended_timestamp: extended_timestamp.then(|| ExtendedTimestamp::modified(modified)),
+            ntfs_timestamp: None,
             filename: name,
-            data: vec![],
-            external_file_attributes: 0o40755 << 16,
+            comment: String::new(),
+            data: ZipFileData::Memory(Vec::new()),
+            external_file_attributes: DEFAULT_FILE_ATTRIBUTES,
+            text: false,
+            encryption: None,
+            owner: None,
+            compression_level: None,
+            preset_dictionary: false,
+            sequence: 0,
+        }
+    }
+}
+
+/// Sort entries into the order they are written in. Reproducible archives are never written in
+/// completion order.
+fn sort_entries(files: &mut [ZipFile], entry_order: EntryOrder, reproducible: bool) {
+    match entry_order {
+        EntryOrder::Completion if !reproducible => {}
+        EntryOrder::Completion | EntryOrder::Insertion => {
+            files.sort_unstable_by_key(|file| file.sequence)
+        }
+        EntryOrder::Name => files.sort_unstable_by(|a, b| {
+            a.filename
+                .cmp(&b.filename)
+                .then(a.sequence.cmp(&b.sequence))
+        }),
+    }
+}
+
+/// Turn a name given by the user into an entry name. The zip format requires forward slashes as
+/// separators and forbids leading slashes, so backslashes are replaced, repeated slashes are
+/// merged and leading slashes are removed. What happens to `.` and `..` components depends on
+/// `sanitization`. Names that don't fit in the 2 byte name length field are rejected with
+/// [`ZipError::NameTooLong`].
+fn normalize_name(name: &str, sanitization: NameSanitization) -> Result<String, ZipError> {
+    let name = sanitize_name(name, sanitization)?;
+    if name.trim().is_empty() {
+        return Err(ZipError::EmptyName);
+    }
+    if name.len() > u16::MAX as usize {
+        return Err(ZipError::NameTooLong);
+    }
+    Ok(name)
+}
+
+fn sanitize_name(name: &str, sanitization: NameSanitization) -> Result<String, ZipError> {
+    let mut slashed = String::with_capacity(name.len());
+    for c in name.chars() {
+        let c = if c == '\\' { '/' } else { c };
+        if c != '/' || !slashed.ends_with('/') {
+            slashed.push(c);
+        }
+    }
+    let name = slashed;
+    let has_drive_letter = {
+        let bytes = name.as_bytes();
+        bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+    };
+    match sanitization {
+        NameSanitization::Off => Ok(name.trim_start_matches('/').to_string()),
+        NameSanitization::Sanitize => {
+            if has_drive_letter {
+                return Err(ZipError::UnsafePath(name));
+            }
+            let mut components = Vec::new();
+            for component in name.split('/') {
+                match component {
+                    "" | "." => {}
+                    // Resolved like a path, `..` at the root would escape it and is dropped
+                    ".." => {
+                        components.pop();
+                    }
+                    component => components.push(component),
+                }
+            }
+            let mut sanitized = components.join("/");
+            if name.ends_with('/') && !sanitized.is_empty() {
+                sanitized.push('/');
+            }
+            Ok(sanitized)
+        }
+        NameSanitization::Strict => {
+            if name.starts_with('/')
+                || has_drive_letter
+                || name.split('/').any(|component| component == "..")
+            {
+                return Err(ZipError::UnsafePath(name));
+            }
+            Ok(name)
+        }
+    }
+}
+
+/// Destination of [`BuiltArchive::write_archive`], which is either a single writer or a series of
+/// volumes
+trait ArchiveWriter: Write {
+    /// Offset in the current volume
+    fn position(&self) -> u64;
+
+    /// Number of the current volume, starting at 0
+    fn volume(&self) -> u32;
+
+    /// Make sure that the next `len` bytes, a header or a record that extractors expect in one
+    /// piece, are written to a single volume
+    fn keep_together(&mut self, len: u64) -> Result<(), ZipError>;
+
+    fn bytes_written(&self) -> u64;
+}
+
+/// Writer that keeps track of its position without needing [`Seek`]
+struct PositionWriter<W> {
+    inner: W,
+    start: u64,
+    position: u64,
+}
+
+impl<W: Write> PositionWriter<W> {
+    fn new(inner: W, position: u64) -> Self {
+        Self {
+            inner,
+            start: position,
+            position,
+        }
+    }
+}
+
+impl<W: Write> ArchiveWriter for PositionWriter<W> {
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn volume(&self) -> u32 {
+        0
+    }
+
+    fn keep_together(&mut self, _len: u64) -> Result<(), ZipError> {
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.position - self.start
+    }
+}
+
+impl<W: Write> Write for PositionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer that starts a new volume with a writer from `make_writer` whenever the current one is
+/// full
+struct SplitWriter<W, F> {
+    make_writer: F,
+    current: W,
+    volume: u32,
+    volume_size: u64,
+    /// Offset in the current volume
+    position: u64,
+    written: u64,
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W, std::io::Error>> SplitWriter<W, F> {
+    fn new(volume_size: u64, mut make_writer: F) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            current: make_writer(0)?,
+            make_writer,
+            volume: 0,
+            volume_size,
+            position: 0,
+            written: 0,
+        })
+    }
+
+    fn next_volume(&mut self) -> Result<(), std::io::Error> {
+        // Volume numbers are 2 bytes wide in the end of central directory record
+        if self.volume + 1 >= u16::MAX as u32 {
+            return Err(std::io::Error::other(
+                "archive needs more than 65535 volumes",
+            ));
+        }
+        self.current.flush()?;
+        self.volume += 1;
+        self.current = (self.make_writer)(self.volume as usize)?;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W, std::io::Error>> ArchiveWriter for SplitWriter<W, F> {
+    fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn volume(&self) -> u32 {
+        self.volume
+    }
+
+    fn keep_together(&mut self, len: u64) -> Result<(), ZipError> {
+        if len > self.volume_size {
+            return Err(ZipError::CannotSplit(
+                "a header is larger than the volume size",
+            ));
         }
+        if self.position + len > self.volume_size {
+            self.next_volume()?;
+        }
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<W: Write, F: FnMut(usize) -> Result<W, std::io::Error>> Write for SplitWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.position == self.volume_size {
+            self.next_volume()?;
+        }
+        let len = (buf.len() as u64).min(self.volume_size - self.position) as usize;
+        let written = self.current.write(&buf[..len])?;
+        self.position += written as u64;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.current.flush()
+    }
+}
+
+/// External file attributes of a file from the filesystem. On Unix the file's mode is stored in
+/// the high 16 bits, elsewhere a regular file with 644 permissions is assumed.
+fn fs_file_attributes(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.mode() << 16
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        DEFAULT_FILE_ATTRIBUTES
+    }
+}
+
+/// External file attributes of a directory from the filesystem. On Unix the directory's mode is
+/// stored in the high 16 bits, elsewhere 755 permissions are assumed.
+fn fs_directory_attributes(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.mode() << 16
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        DIRECTORY_ATTRIBUTES
+    }
+}
+
+/// External file attributes of a file from the filesystem in a reproducible archive, with 644 or
+/// 755 permissions depending on whether the owner can execute the file
+fn reproducible_file_attributes(metadata: &Metadata) -> u32 {
+    if fs_file_attributes(metadata) & (0o100 << 16) != 0 {
+        EXECUTABLE_FILE_ATTRIBUTES
+    } else {
+        DEFAULT_FILE_ATTRIBUTES
+    }
+}
+
+/// Reader that decompresses data compressed with `compression_type` from `reader`
+fn decoder<'r, R: Read + 'r>(
+    compression_type: CompressionType,
+    reader: R,
+) -> Result<Box<dyn Read + 'r>, std::io::Error> {
+    Ok(match compression_type {
+        CompressionType::Stored => Box::new(reader),
+        CompressionType::Deflate => Box::new(DeflateDecoder::new(reader)),
+        #[cfg(feature = "bzip2")]
+        CompressionType::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
+}
+
+/// Guess whether `data` is text. Empty data is not.
+fn is_text(data: &[u8]) -> bool {
+    !data.is_empty() && !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Reader that counts how many bytes were read through it
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
     }
 }
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reader that fails once the archive is cancelled, so that compressing stops early
+struct CancellableReader<'t, R> {
+    inner: R,
+    cancel_token: &'t CancelToken,
+}
+
+impl<R: Read> Read for CancellableReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancel_token.is_cancelled() {
+            return Err(std::io::Error::other("archive was cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Value of the version made by field
+fn version_made_by(host_system: HostSystem, spec_version: u8) -> u16 {
+    (host_system as u16) << 8 | spec_version as u16
+}
+
+/// Value for a 32-bit field that is replaced by a ZIP64 value when it doesn't fit
+fn zip64_u32(value: u64) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+/// Value for a 16-bit entry count that is replaced by the one in the ZIP64 end of central
+/// directory record when it doesn't fit
+fn zip64_u16(value: usize) -> u16 {
+    u16::try_from(value).unwrap_or(u16::MAX)
+}
+
+/// Whether the end of central directory record can't describe the central directory and a ZIP64
+/// record has to be written before it
+fn needs_zip64_end_of_central_dir(
+    entry_count: usize,
+    central_dir_offset: u64,
+    central_dir_size: u64,
+) -> bool {
+    entry_count >= u16::MAX as usize
+        || central_dir_offset >= u32::MAX as u64
+        || central_dir_size >= u32::MAX as u64
+}
+
+fn write_zip64_extra_field(buf: &mut Vec<u8>, values: &[u64]) {
+    buf.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+    buf.extend_from_slice(&((values.len() * 8) as u16).to_le_bytes());
+    for value in values {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// 100 nanosecond intervals since 1601-01-01, the Windows FILETIME format. Times before 1601 are
+/// clamped to it.
+fn filetime(time: SystemTime) -> u64 {
+    let intervals = |duration: Duration| {
+        duration.as_secs().saturating_mul(10_000_000) + u64::from(duration.subsec_nanos() / 100)
+    };
+    let epoch = FILETIME_UNIX_EPOCH * 10_000_000;
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => epoch.saturating_add(intervals(since)),
+        Err(before) => epoch.saturating_sub(intervals(before.duration())),
+    }
+}
+
+/// Seconds since the Unix epoch, clamped to the range of the extended timestamp field
+fn unix_timestamp(time: SystemTime) -> i32 {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_secs()).unwrap_or(i64::MAX),
+        Err(before) => -i64::try_from(before.duration().as_secs()).unwrap_or(i64::MAX),
+    };
+    secs.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+/// Convert a point in time to packed MS-DOS date and time, in that order. DOS timestamps have a
+/// 2 second resolution and can't represent anything before 1980 or after 2107, so times outside of
+/// that range are clamped. The time is treated as UTC.
+fn dos_date_time(time: SystemTime) -> (u16, u16) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // Days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    if year < 1980 {
+        return (1 << 5 | 1, 0);
+    }
+    if year > 2107 {
+        return (127 << 9 | 12 << 5 | 31, 23 << 11 | 59 << 5 | 29);
+    }
+
+    let date = ((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16;
+    let time = ((secs_of_day / 3600) as u16) << 11
+        | ((secs_of_day / 60 % 60) as u16) << 5
+        | (secs_of_day % 60 / 2) as u16;
+    (date, time)
+}
@@ -0,0 +1,265 @@
+//! Minimal reading of existing archives. The end of central directory records and the central
+//! directory are parsed, entry data is only read when a single entry is extracted.
+
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+use flate2::CrcReader;
+
+use crate::{
+    decoder, CompressionType, EntryInfo, ZipError, DIRECTORY_ENTRY_SIGNATURE,
+    END_OF_CENTRAL_DIR_SIGNATURE, END_OF_CENTRAL_DIR_SIZE, FILE_RECORD_SIGNATURE, FILE_RECORD_SIZE,
+    FLAG_ENCRYPTED, ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIGNATURE, ZIP64_END_OF_CENTRAL_DIR_SIGNATURE,
+    ZIP64_EXTRA_FIELD_ID,
+};
+
+const ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIZE: u64 = 20;
+const DIRECTORY_ENTRY_SIZE: usize = crate::DIRECTORY_ENTRY_SIZE as usize;
+
+/// Central directory of an existing archive
+#[derive(Debug)]
+pub(crate) struct CentralDirectory {
+    pub(crate) offset: u64,
+    pub(crate) entries: Vec<CentralDirectoryEntry>,
+    pub(crate) comment: Vec<u8>,
+}
+
+/// Central directory entry of an existing archive
+#[derive(Debug)]
+pub(crate) struct CentralDirectoryEntry {
+    pub(crate) filename: String,
+    /// General purpose bit flags
+    pub(crate) flags: u16,
+    pub(crate) compression_method: u16,
+    pub(crate) crc: u32,
+    pub(crate) compressed_size: u64,
+    pub(crate) uncompressed_size: u64,
+    pub(crate) local_header_offset: u64,
+    /// The whole entry as it was read, written back unchanged when appending
+    pub(crate) raw: Vec<u8>,
+}
+
+fn invalid(message: &'static str) -> ZipError {
+    ZipError::InvalidArchive(message)
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+impl CentralDirectory {
+    /// Find the end of central directory record and read the central directory it points to
+    pub(crate) fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, ZipError> {
+        let archive_len = reader.seek(SeekFrom::End(0))?;
+        if archive_len < END_OF_CENTRAL_DIR_SIZE {
+            return Err(invalid("end of central directory not found"));
+        }
+
+        // The record is followed by a comment of at most 65535 bytes, search backwards from the
+        // last position it can start at
+        let tail_len = archive_len.min(END_OF_CENTRAL_DIR_SIZE + u16::MAX as u64);
+        let tail_start = archive_len - tail_len;
+        let mut tail = vec![0; tail_len as usize];
+        reader.seek(SeekFrom::Start(tail_start))?;
+        reader.read_exact(&mut tail)?;
+        let eocd_pos = (0..=tail.len() - END_OF_CENTRAL_DIR_SIZE as usize)
+            .rev()
+            .find(|&pos| {
+                u32_at(&tail, pos) == END_OF_CENTRAL_DIR_SIGNATURE
+                    && pos + END_OF_CENTRAL_DIR_SIZE as usize + u16_at(&tail, pos + 20) as usize
+                        == tail.len()
+            })
+            .ok_or(invalid("end of central directory not found"))?;
+        let eocd = &tail[eocd_pos..];
+        if u16_at(eocd, 4) != 0 || u16_at(eocd, 6) != 0 {
+            return Err(invalid("multi-disk archives are not supported"));
+        }
+        let mut entry_count = u16_at(eocd, 10) as u64;
+        let mut size = u32_at(eocd, 12) as u64;
+        let mut offset = u32_at(eocd, 16) as u64;
+        let comment = eocd[END_OF_CENTRAL_DIR_SIZE as usize..].to_vec();
+
+        let eocd_offset = tail_start + eocd_pos as u64;
+        if eocd_offset >= ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIZE {
+            let mut locator = [0; ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIZE as usize];
+            reader.seek(SeekFrom::Start(
+                eocd_offset - ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIZE,
+            ))?;
+            reader.read_exact(&mut locator)?;
+            if u32_at(&locator, 0) == ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIGNATURE {
+                let mut record = [0; 56];
+                reader.seek(SeekFrom::Start(u64_at(&locator, 8)))?;
+                reader.read_exact(&mut record)?;
+                if u32_at(&record, 0) != ZIP64_END_OF_CENTRAL_DIR_SIGNATURE {
+                    return Err(invalid("ZIP64 end of central directory not found"));
+                }
+                entry_count = u64_at(&record, 32);
+                size = u64_at(&record, 40);
+                offset = u64_at(&record, 48);
+            }
+        }
+        if offset.checked_add(size).is_none_or(|end| end > eocd_offset) {
+            return Err(invalid("central directory is out of bounds"));
+        }
+
+        let mut directory = vec![0; size as usize];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut directory)?;
+        let mut entries = Vec::new();
+        let mut rest = &directory[..];
+        for _ in 0..entry_count {
+            let (entry, remaining) = CentralDirectoryEntry::parse(rest)?;
+            entries.push(entry);
+            rest = remaining;
+        }
+
+        Ok(Self {
+            offset,
+            entries,
+            comment,
+        })
+    }
+}
+
+impl CentralDirectoryEntry {
+    /// Parse the entry at the start of `buf`, returning it and the bytes after it
+    fn parse(buf: &[u8]) -> Result<(Self, &[u8]), ZipError> {
+        if buf.len() < DIRECTORY_ENTRY_SIZE || u32_at(buf, 0) != DIRECTORY_ENTRY_SIGNATURE {
+            return Err(invalid("malformed central directory entry"));
+        }
+        let name_len = u16_at(buf, 28) as usize;
+        let extra_len = u16_at(buf, 30) as usize;
+        let comment_len = u16_at(buf, 32) as usize;
+        let len = DIRECTORY_ENTRY_SIZE + name_len + extra_len + comment_len;
+        if buf.len() < len {
+            return Err(invalid("malformed central directory entry"));
+        }
+        let name = &buf[DIRECTORY_ENTRY_SIZE..DIRECTORY_ENTRY_SIZE + name_len];
+        let mut extra =
+            &buf[DIRECTORY_ENTRY_SIZE + name_len..DIRECTORY_ENTRY_SIZE + name_len + extra_len];
+
+        let mut compressed_size = u32_at(buf, 20) as u64;
+        let mut uncompressed_size = u32_at(buf, 24) as u64;
+        let mut local_header_offset = u32_at(buf, 42) as u64;
+        // Values that don't fit are in the ZIP64 extra field, in this order
+        while extra.len() >= 4 {
+            let id = u16_at(extra, 0);
+            let data_len = (u16_at(extra, 2) as usize).min(extra.len() - 4);
+            let mut data = &extra[4..4 + data_len];
+            if id == ZIP64_EXTRA_FIELD_ID {
+                for value in [
+                    &mut uncompressed_size,
+                    &mut compressed_size,
+                    &mut local_header_offset,
+                ] {
+                    if *value == u32::MAX as u64 && data.len() >= 8 {
+                        *value = u64_at(data, 0);
+                        data = &data[8..];
+                    }
+                }
+            }
+            extra = &extra[4 + data_len..];
+        }
+
+        let entry = Self {
+            filename: String::from_utf8_lossy(name).into_owned(),
+            flags: u16_at(buf, 8),
+            compression_method: u16_at(buf, 10),
+            crc: u32_at(buf, 16),
+            compressed_size,
+            uncompressed_size,
+            local_header_offset,
+            raw: buf[..len].to_vec(),
+        };
+        Ok((entry, &buf[len..]))
+    }
+
+    /// Seek `reader` to the entry's data and return a reader that decompresses it, checking the
+    /// CRC and size once the end is reached
+    pub(crate) fn reader<'r, R: Read + Seek>(
+        &self,
+        reader: &'r mut R,
+    ) -> Result<EntryReader<'r>, ZipError> {
+        if self.flags & FLAG_ENCRYPTED != 0 {
+            return Err(invalid("encrypted entries are not supported"));
+        }
+        let compression_type = CompressionType::from_method(self.compression_method)
+            .ok_or(ZipError::UnsupportedCompression(self.compression_method))?;
+
+        // The name and extra field of the local header can differ from the central directory
+        let mut header = [0; FILE_RECORD_SIZE as usize];
+        reader.seek(SeekFrom::Start(self.local_header_offset))?;
+        reader.read_exact(&mut header)?;
+        if u32_at(&header, 0) != FILE_RECORD_SIGNATURE {
+            return Err(invalid("local file header not found"));
+        }
+        let variable_len = u16_at(&header, 26) as i64 + u16_at(&header, 28) as i64;
+        reader.seek(SeekFrom::Current(variable_len))?;
+
+        let compressed = reader.take(self.compressed_size);
+        Ok(EntryReader {
+            decoder: CrcReader::new(decoder(compression_type, compressed)?),
+            read: 0,
+            crc: self.crc,
+            uncompressed_size: self.uncompressed_size,
+        })
+    }
+
+    /// Read the entry's data from `reader` and decompress it, checking the CRC and size
+    pub(crate) fn extract<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<u8>, ZipError> {
+        let mut decoder = self.reader(reader)?;
+        let mut data = Vec::with_capacity(self.uncompressed_size.min(1 << 30) as usize);
+        match decoder.read_to_end(&mut data) {
+            Ok(_) => Ok(data),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => {
+                Err(ZipError::CorruptEntry(self.filename.clone()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn info(&self) -> EntryInfo {
+        EntryInfo {
+            id: None,
+            name: self.filename.clone(),
+            compression_method: self.compression_method,
+            crc: self.crc,
+            compressed_size: self.compressed_size,
+            uncompressed_size: self.uncompressed_size,
+        }
+    }
+}
+
+/// Decompressing reader of a single entry, returned by [`CentralDirectoryEntry::reader`]. Once
+/// the data ends, a CRC or size that doesn't match the central directory is reported as an
+/// [`ErrorKind::InvalidData`] error.
+pub(crate) struct EntryReader<'r> {
+    decoder: CrcReader<Box<dyn Read + 'r>>,
+    read: u64,
+    crc: u32,
+    uncompressed_size: u64,
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.decoder.read(buf)?;
+        self.read += read as u64;
+        let ended = read == 0 && !buf.is_empty();
+        if (ended || self.read > self.uncompressed_size)
+            && (self.decoder.crc().sum() != self.crc || self.read != self.uncompressed_size)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "entry data doesn't match its CRC or size",
+            ));
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,1550 @@
+//! Tests that write archives and read them back with [`crate::read`]
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    read::{CentralDirectory, CentralDirectoryEntry},
+    CompressionType, ZipArchive, ZipError, END_OF_CENTRAL_DIR_SIGNATURE, FILE_RECORD_SIGNATURE,
+    FLAG_DATA_DESCRIPTOR, MIN_VOLUME_SIZE, SPLIT_ARCHIVE_SIGNATURE,
+    ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIGNATURE, ZIP64_EXTRA_FIELD_ID,
+};
+
+/// Compress `data` with `compression_type` and read it back with `list_entries` and
+/// `extract_entry`
+fn roundtrip(compression_type: CompressionType, data: &[u8]) -> Vec<u8> {
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_compression_type(compression_type);
+    archive.add_file_from_slice(data, "file").unwrap();
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    let entries = ZipArchive::list_entries(&mut bytes).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].uncompressed_size, data.len() as u64);
+    ZipArchive::extract_entry(&mut bytes, "file").unwrap()
+}
+
+#[test]
+fn crc_matches_known_value() {
+    // CRC-32 check value from the specification of the algorithm
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"123456789", "check").unwrap();
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    let entries = ZipArchive::list_entries(&mut bytes).unwrap();
+    assert_eq!(entries[0].crc, 0xCBF43926);
+}
+
+#[test]
+fn crc_roundtrip() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    assert_eq!(roundtrip(CompressionType::Stored, &data), data);
+    assert_eq!(roundtrip(CompressionType::Deflate, &data), data);
+    #[cfg(feature = "bzip2")]
+    assert_eq!(roundtrip(CompressionType::Bzip2, &data), data);
+    #[cfg(feature = "zstd")]
+    assert_eq!(roundtrip(CompressionType::Zstd, &data), data);
+}
+
+#[test]
+fn crc_mismatch_is_corrupt() {
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_compression_type(CompressionType::Stored);
+    archive
+        .add_file_from_slice(b"some stored data", "file")
+        .unwrap();
+    let mut bytes = archive.to_bytes().unwrap();
+    let at = bytes
+        .windows(4)
+        .position(|window| window == b"some")
+        .unwrap();
+    bytes[at] ^= 1;
+    let result = ZipArchive::extract_entry(&mut Cursor::new(bytes), "file");
+    assert!(matches!(result, Err(ZipError::CorruptEntry(name)) if name == "file"));
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+/// Data of the extra field with `id` in `extra`
+fn extra_field(extra: &[u8], id: u16) -> Option<&[u8]> {
+    let mut rest = extra;
+    while rest.len() >= 4 {
+        let len = u16_at(rest, 2) as usize;
+        if u16_at(rest, 0) == id {
+            return Some(&rest[4..4 + len]);
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+/// Extra fields of a raw central directory entry
+fn central_extra(raw: &[u8]) -> &[u8] {
+    let start = 46 + u16_at(raw, 28) as usize;
+    &raw[start..start + u16_at(raw, 30) as usize]
+}
+
+/// Extra fields of a local header
+fn local_extra(header: &[u8]) -> &[u8] {
+    &header[30 + u16_at(header, 26) as usize..]
+}
+
+/// Stored data of an entry, found through its local header
+fn entry_data<'b>(bytes: &'b [u8], entry: &CentralDirectoryEntry) -> &'b [u8] {
+    let header = &bytes[entry.local_header_offset as usize..];
+    let start = 30 + u16_at(header, 26) as usize + u16_at(header, 28) as usize;
+    &header[start..start + entry.compressed_size as usize]
+}
+
+/// Xorshift generator, so the randomized tests are the same on every run
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// Directory for files that are added from the filesystem, removed when it's dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rayonzip-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+        Self(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Create a file in the directory and return its path
+    fn file(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Central directory entries of a written archive
+fn read_entries(bytes: &[u8]) -> Vec<CentralDirectoryEntry> {
+    CentralDirectory::read(&mut Cursor::new(bytes))
+        .unwrap()
+        .entries
+}
+
+fn find<'e>(entries: &'e [CentralDirectoryEntry], name: &str) -> &'e CentralDirectoryEntry {
+    entries
+        .iter()
+        .find(|entry| entry.filename == name)
+        .unwrap_or_else(|| panic!("no entry named {name}"))
+}
+
+/// Local header of an entry, up to the end of its extra field
+fn local_header<'b>(bytes: &'b [u8], entry: &CentralDirectoryEntry) -> &'b [u8] {
+    let header = &bytes[entry.local_header_offset as usize..];
+    &header[..30 + u16_at(header, 26) as usize + u16_at(header, 28) as usize]
+}
+
+#[test]
+fn roundtrip_entry_kinds() {
+    let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+    let mut archive = ZipArchive::with_global_pool();
+    archive.add_directory("dir").unwrap();
+    archive.add_empty_file("dir/empty").unwrap();
+    archive.add_file_from_slice(b"slice data", "slice").unwrap();
+    archive
+        .add_file_from_reader(Cursor::new(vec![7; 50_000]), "reader")
+        .unwrap();
+    archive
+        .add_file_from_fs(manifest.as_ref(), "Cargo.toml")
+        .unwrap();
+    archive.add_symlink("slice", "link").unwrap();
+    let built = archive.build().unwrap();
+    built.verify_roundtrip().unwrap();
+
+    let mut bytes = Cursor::new(built.to_bytes().unwrap());
+    assert_eq!(bytes.get_ref().len() as u64, built.total_size());
+    let mut names: Vec<_> = ZipArchive::list_entries(&mut bytes)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["Cargo.toml", "dir/", "dir/empty", "link", "reader", "slice"]
+    );
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "slice").unwrap(),
+        b"slice data"
+    );
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "link").unwrap(),
+        b"slice"
+    );
+    assert!(ZipArchive::extract_entry(&mut bytes, "dir/empty")
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "Cargo.toml").unwrap(),
+        std::fs::read(manifest).unwrap()
+    );
+    let mut reader = ZipArchive::entry_reader(&mut bytes, "reader").unwrap();
+    let mut data = Vec::new();
+    std::io::Read::read_to_end(&mut reader, &mut data).unwrap();
+    assert_eq!(data, vec![7; 50_000]);
+    drop(reader);
+    assert!(matches!(
+        ZipArchive::extract_entry(&mut bytes, "missing"),
+        Err(ZipError::EntryNotFound(_))
+    ));
+}
+
+#[test]
+fn randomized_roundtrip() {
+    let mut rng = Rng(0x9E3779B97F4A7C15);
+    for round in 0..32 {
+        let mut archive = if round % 2 == 0 {
+            ZipArchive::new_sequential()
+        } else {
+            ZipArchive::with_global_pool()
+        };
+        let mut expected = Vec::new();
+        for index in 0..1 + rng.below(8) {
+            let len = rng.below(20_000) as usize;
+            let data = if rng.below(2) == 0 {
+                rng.bytes(len)
+            } else {
+                vec![rng.next() as u8; len]
+            };
+            let name = format!("dir{}/file{index}", rng.below(3));
+            archive.set_compression_type(if rng.below(2) == 0 {
+                CompressionType::Stored
+            } else {
+                CompressionType::Deflate
+            });
+            archive.add_file_from_slice(&data, &name).unwrap();
+            expected.push((name, data));
+        }
+        let built = archive.build().unwrap();
+        built.verify_roundtrip().unwrap();
+        let mut bytes = Cursor::new(built.to_bytes().unwrap());
+        assert_eq!(
+            ZipArchive::list_entries(&mut bytes).unwrap().len(),
+            expected.len()
+        );
+        for (name, data) in expected {
+            assert_eq!(ZipArchive::extract_entry(&mut bytes, &name).unwrap(), data);
+        }
+    }
+}
+
+#[test]
+fn zip64_extra_field_at_size_threshold() {
+    for (uncompressed_size, zip64) in [(u32::MAX as u64 - 1, false), (u32::MAX as u64, true)] {
+        let mut archive = ZipArchive::new_sequential();
+        archive
+            .add_precompressed(
+                vec![0; 16],
+                CompressionType::Deflate,
+                0,
+                uncompressed_size,
+                "big",
+            )
+            .unwrap();
+        let built = archive.build().unwrap();
+        let mut bytes = Cursor::new(Vec::new());
+        let stats = built.write_to(&mut bytes).unwrap();
+        assert_eq!(stats.zip64_used, zip64);
+        let central_directory = CentralDirectory::read(&mut bytes).unwrap();
+        let entry = &central_directory.entries[0];
+        assert_eq!(entry.uncompressed_size, uncompressed_size);
+        assert_eq!(
+            extra_field(central_extra(&entry.raw), ZIP64_EXTRA_FIELD_ID).is_some(),
+            zip64
+        );
+    }
+}
+
+#[test]
+fn zip64_end_record_at_entry_threshold() {
+    for (entry_count, zip64) in [(u16::MAX as usize - 1, false), (u16::MAX as usize, true)] {
+        let mut archive = ZipArchive::new_sequential();
+        for index in 0..entry_count {
+            archive.add_empty_file(&index.to_string()).unwrap();
+        }
+        let mut bytes = Cursor::new(Vec::new());
+        let stats = archive.write(&mut bytes).unwrap();
+        assert_eq!(stats.zip64_used, zip64);
+        let bytes = bytes.into_inner();
+        let eocd = bytes.len() - 22;
+        assert_eq!(u32_at(&bytes, eocd), END_OF_CENTRAL_DIR_SIGNATURE);
+        assert_eq!(
+            u32_at(&bytes, eocd - 20) == ZIP64_END_OF_CENTRAL_DIR_LOCATOR_SIGNATURE,
+            zip64
+        );
+        let entries = ZipArchive::list_entries(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(entries.len(), entry_count);
+    }
+}
+
+#[test]
+fn streaming_uses_data_descriptors() {
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 13) as u8).collect();
+    let mut archive = ZipArchive::with_global_pool();
+    archive.add_file_from_slice(&data, "first").unwrap();
+    archive.add_file_from_slice(&data, "second").unwrap();
+    let built = archive.build().unwrap();
+    let mut bytes = Vec::new();
+    built.write_streaming_to(&mut bytes).unwrap();
+    assert_eq!(bytes.len() as u64, built.streaming_size());
+    assert!(built.streaming_size() > built.total_size());
+
+    assert_eq!(u32_at(&bytes, 0), FILE_RECORD_SIGNATURE);
+    assert_ne!(u16_at(&bytes, 6) & FLAG_DATA_DESCRIPTOR, 0);
+    let mut bytes = Cursor::new(bytes);
+    let central_directory = CentralDirectory::read(&mut bytes).unwrap();
+    for entry in &central_directory.entries {
+        assert_ne!(entry.flags & FLAG_DATA_DESCRIPTOR, 0);
+    }
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "first").unwrap(),
+        data
+    );
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "second").unwrap(),
+        data
+    );
+}
+
+/// Volume of a split archive, written into the last buffer of the shared list
+struct Volume(Rc<RefCell<Vec<Vec<u8>>>>);
+
+impl Write for Volume {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .borrow_mut()
+            .last_mut()
+            .unwrap()
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_split(archive: ZipArchive, volume_size: u64) -> Vec<Vec<u8>> {
+    let volumes = Rc::new(RefCell::new(Vec::new()));
+    archive
+        .build()
+        .unwrap()
+        .write_split_to(volume_size, |index| {
+            let mut volumes_ref = volumes.borrow_mut();
+            assert_eq!(volumes_ref.len(), index);
+            volumes_ref.push(Vec::new());
+            Ok(Volume(volumes.clone()))
+        })
+        .unwrap();
+    Rc::try_unwrap(volumes).unwrap().into_inner()
+}
+
+#[test]
+fn split_output() {
+    let mut rng = Rng(0xDEADBEEF);
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_compression_type(CompressionType::Stored);
+    for index in 0..3 {
+        archive
+            .add_file_from_slice(&rng.bytes(100_000), &format!("file{index}"))
+            .unwrap();
+    }
+    let volumes = write_split(archive, MIN_VOLUME_SIZE);
+    assert!(volumes.len() >= 5);
+    assert_eq!(u32_at(&volumes[0], 0), SPLIT_ARCHIVE_SIGNATURE);
+    for volume in &volumes {
+        assert!(volume.len() as u64 <= MIN_VOLUME_SIZE);
+    }
+    let last = volumes.last().unwrap();
+    let eocd = last.len() - 22;
+    assert_eq!(u32_at(last, eocd), END_OF_CENTRAL_DIR_SIGNATURE);
+    assert_eq!(u16_at(last, eocd + 4) as usize, volumes.len() - 1);
+    assert_eq!(u16_at(last, eocd + 10), 3);
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"small", "small").unwrap();
+    let volumes = write_split(archive, MIN_VOLUME_SIZE);
+    assert_eq!(volumes.len(), 1);
+    let mut bytes = Cursor::new(volumes.into_iter().next().unwrap());
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "small").unwrap(),
+        b"small"
+    );
+}
+
+/// Archive with a long comment, so appending with a shorter one leaves less to write
+fn archive_to_append_to() -> Vec<u8> {
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_comment(&"long comment ".repeat(100)).unwrap();
+    archive.add_file_from_slice(b"old data", "old").unwrap();
+    archive.to_bytes().unwrap()
+}
+
+#[test]
+fn append_truncates_file() {
+    let path = std::env::temp_dir().join(format!("rayonzip-append-{}.zip", std::process::id()));
+    std::fs::write(&path, archive_to_append_to()).unwrap();
+    let mut file = File::options().read(true).write(true).open(&path).unwrap();
+    let mut archive = ZipArchive::append_with_global_pool(&mut file).unwrap();
+    archive.set_comment("short").unwrap();
+    archive.add_file_from_slice(b"new data", "new").unwrap();
+    let stats = archive.write_to_file(&mut file).unwrap();
+    drop(file);
+
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(bytes.len() as u64, stats.end_offset);
+    let mut bytes = Cursor::new(bytes);
+    assert_eq!(
+        CentralDirectory::read(&mut bytes).unwrap().comment,
+        b"short"
+    );
+    assert_eq!(ZipArchive::list_entries(&mut bytes).unwrap().len(), 2);
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "old").unwrap(),
+        b"old data"
+    );
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "new").unwrap(),
+        b"new data"
+    );
+}
+
+#[test]
+fn append_reports_end_offset() {
+    let mut bytes = Cursor::new(archive_to_append_to());
+    let old_len = bytes.get_ref().len() as u64;
+    let mut archive = ZipArchive::append_with_global_pool(&mut bytes).unwrap();
+    archive.set_comment("short").unwrap();
+    archive.add_file_from_slice(b"new data", "new").unwrap();
+    let stats = archive.write(&mut bytes).unwrap();
+    assert!(stats.end_offset < old_len);
+
+    let mut bytes = bytes.into_inner();
+    bytes.truncate(stats.end_offset as usize);
+    let mut bytes = Cursor::new(bytes);
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "old").unwrap(),
+        b"old data"
+    );
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "new").unwrap(),
+        b"new data"
+    );
+}
+
+#[cfg(any(feature = "aes", feature = "zipcrypto"))]
+fn encrypted_entry(
+    encryption_method: crate::EncryptionMethod,
+) -> (Vec<u8>, crate::read::CentralDirectoryEntry) {
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_compression_type(CompressionType::Stored);
+    archive.set_encryption_method(encryption_method);
+    archive.set_password(Some("password"));
+    archive
+        .add_file_from_slice(b"hidden contents", "secret")
+        .unwrap();
+    let built = archive.build().unwrap();
+    built.verify_roundtrip().unwrap();
+    let mut bytes = Cursor::new(built.to_bytes().unwrap());
+    let entry = CentralDirectory::read(&mut bytes)
+        .unwrap()
+        .entries
+        .remove(0);
+    assert_ne!(entry.flags & crate::FLAG_ENCRYPTED, 0);
+    assert!(!bytes
+        .get_ref()
+        .windows(6)
+        .any(|window| window == &b"hidden contents"[..6]));
+    assert!(matches!(
+        ZipArchive::extract_entry(&mut bytes, "secret"),
+        Err(ZipError::InvalidArchive(_))
+    ));
+    (bytes.into_inner(), entry)
+}
+
+#[cfg(feature = "zipcrypto")]
+#[test]
+fn zipcrypto_entry() {
+    let (_, entry) = encrypted_entry(crate::EncryptionMethod::ZipCrypto);
+    assert_eq!(entry.compression_method, 0);
+    assert_eq!(
+        entry.compressed_size,
+        (b"hidden contents".len() + crate::zipcrypto::HEADER_LEN) as u64
+    );
+}
+
+#[cfg(feature = "aes")]
+#[test]
+fn aes_entry() {
+    let (_, entry) = encrypted_entry(crate::EncryptionMethod::Aes256);
+    assert_eq!(entry.compression_method, 99);
+    assert_eq!(
+        entry.compressed_size,
+        (b"hidden contents".len() + crate::aes::OVERHEAD) as u64
+    );
+}
+
+/// Reader that fails on the first read
+struct FailingReader;
+
+impl std::io::Read for FailingReader {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("read failed"))
+    }
+}
+
+#[test]
+fn failed_entry_keeps_failing() {
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"data", "good").unwrap();
+    let failed = archive.add_file_from_reader(FailingReader, "bad").unwrap();
+    let is_failed = |e: ZipError| matches!(e, ZipError::EntryFailed(id, e) if id == failed && matches!(*e, ZipError::Io(_)));
+    assert!(is_failed(archive.collect_entries().unwrap_err()));
+    assert!(is_failed(archive.collect_entries().unwrap_err()));
+    assert!(is_failed(archive.build().err().unwrap()));
+}
+
+#[test]
+fn auto_store_applies_to_slices_only() {
+    let data = Rng(0x5EED).bytes(10_000);
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_auto_store(true);
+    archive.add_file_from_slice(&data, "slice").unwrap();
+    archive.add_file_from_slice(&[0; 10_000], "zeros").unwrap();
+    archive
+        .add_file_from_reader(Cursor::new(data.clone()), "reader")
+        .unwrap();
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    let central_directory = CentralDirectory::read(&mut bytes).unwrap();
+    let entry = |name| {
+        central_directory
+            .entries
+            .iter()
+            .find(|entry| entry.filename == name)
+            .unwrap()
+    };
+    assert_eq!(entry("slice").compression_method, 0);
+    assert_eq!(entry_data(bytes.get_ref(), entry("slice")), data);
+    assert_eq!(entry("zeros").compression_method, 8);
+    assert!(entry("zeros").compressed_size < 10_000);
+    assert_eq!(entry("reader").compression_method, 8);
+    assert!(entry("reader").compressed_size > data.len() as u64);
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "slice").unwrap(),
+        data
+    );
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "reader").unwrap(),
+        data
+    );
+}
+
+#[test]
+fn deflate_level_flags() {
+    for (level, flags) in [
+        (0, 0),
+        (1, 6),
+        (2, 4),
+        (3, 0),
+        (6, 0),
+        (7, 0),
+        (8, 2),
+        (9, 2),
+    ] {
+        let mut archive = ZipArchive::new_sequential();
+        archive.set_compression_level(level);
+        archive.add_file_from_slice(&[1; 1000], "file").unwrap();
+        let bytes = archive.to_bytes().unwrap();
+        let mut bytes = Cursor::new(bytes);
+        let entry = &CentralDirectory::read(&mut bytes).unwrap().entries[0];
+        assert_eq!(entry.compression_method, 8, "level {level}");
+        assert_eq!(entry.flags & 6, flags, "level {level}");
+        assert_eq!(u16_at(bytes.get_ref(), 6), entry.flags, "level {level}");
+    }
+}
+
+#[test]
+fn entry_comment() {
+    let mut archive = ZipArchive::new_sequential();
+    let options = crate::FileOptions::new().comment("from https://example.com");
+    archive
+        .add_file_from_slice_with(b"data", "file", options)
+        .unwrap();
+    let long = crate::FileOptions::new().comment(&"x".repeat(u16::MAX as usize + 1));
+    assert!(matches!(
+        archive.add_file_from_slice_with(b"data", "long", long),
+        Err(ZipError::CommentTooLong)
+    ));
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    let entries = CentralDirectory::read(&mut bytes).unwrap().entries;
+    assert_eq!(entries.len(), 1);
+    let raw = &entries[0].raw;
+    let comment_start = 46 + u16_at(raw, 28) as usize + u16_at(raw, 30) as usize;
+    assert_eq!(u16_at(raw, 32), 24);
+    assert_eq!(&raw[comment_start..], b"from https://example.com");
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn write_async_roundtrip() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut archive = ZipArchive::with_global_pool();
+    archive.add_file_from_slice(&[5; 200_000], "large").unwrap();
+    archive.add_file_from_slice(b"small", "small").unwrap();
+    let mut bytes = Vec::new();
+    let stats = runtime.block_on(archive.write_async(&mut bytes)).unwrap();
+    assert_eq!(stats.bytes_written, bytes.len() as u64);
+    let mut bytes = Cursor::new(bytes);
+    let central_directory = CentralDirectory::read(&mut bytes).unwrap();
+    assert_eq!(central_directory.entries.len(), 2);
+    for entry in &central_directory.entries {
+        assert_ne!(entry.flags & FLAG_DATA_DESCRIPTOR, 0);
+    }
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "large").unwrap(),
+        vec![5; 200_000]
+    );
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "small").unwrap(),
+        b"small"
+    );
+}
+
+#[cfg(feature = "deflate-dictionary")]
+#[test]
+fn deflate_dictionary() {
+    let dictionary = br#"{"name": "", "version": "", "enabled": true, "dependencies": []}"#;
+    let config = br#"{"name": "config", "version": "1.0", "enabled": true, "dependencies": []}"#;
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(config, "plain.json").unwrap();
+    archive.set_deflate_dictionary(Some(dictionary));
+    archive
+        .add_file_from_slice(config, "dictionary.json")
+        .unwrap();
+    let built = archive.build().unwrap();
+    built.verify_roundtrip().unwrap();
+    let bytes = built.to_bytes().unwrap();
+    let entries = CentralDirectory::read(&mut Cursor::new(&bytes))
+        .unwrap()
+        .entries;
+    let entry = |name| entries.iter().find(|entry| entry.filename == name).unwrap();
+    let plain = entry("plain.json");
+    let with_dictionary = entry("dictionary.json");
+    assert_eq!(with_dictionary.compression_method, 8);
+    assert!(with_dictionary.compressed_size < plain.compressed_size);
+
+    let mut decompress = flate2::Decompress::new(false);
+    decompress.set_dictionary(dictionary).unwrap();
+    let mut data = Vec::with_capacity(config.len());
+    decompress
+        .decompress_vec(
+            entry_data(&bytes, with_dictionary),
+            &mut data,
+            flate2::FlushDecompress::Finish,
+        )
+        .unwrap();
+    assert_eq!(data, config);
+}
+
+/// Names of the entries of a written archive, in the order they were written
+fn entry_names(archive: ZipArchive) -> Vec<String> {
+    let bytes = archive.to_bytes().unwrap();
+    CentralDirectory::read(&mut Cursor::new(bytes))
+        .unwrap()
+        .entries
+        .into_iter()
+        .map(|entry| entry.filename)
+        .collect()
+}
+
+#[test]
+fn sanitized_names() {
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_name_sanitization(crate::NameSanitization::Sanitize);
+    for name in ["a/../b", "../../etc/passwd", "/abs/./c", "d/e/../../f/..x"] {
+        archive.add_file_from_slice(b"data", name).unwrap();
+    }
+    archive.add_directory("g/h/../i").unwrap();
+    assert!(matches!(
+        archive.add_file_from_slice(b"data", "C:/windows"),
+        Err(ZipError::UnsafePath(_))
+    ));
+    assert!(matches!(
+        archive.add_file_from_slice(b"data", "a/.."),
+        Err(ZipError::EmptyName)
+    ));
+    assert_eq!(
+        entry_names(archive),
+        ["b", "etc/passwd", "abs/c", "f/..x", "g/i/"]
+    );
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_name_sanitization(crate::NameSanitization::Strict);
+    for name in ["../a", "a/../b", "/a", "C:/a"] {
+        assert!(matches!(
+            archive.add_file_from_slice(b"data", name),
+            Err(ZipError::UnsafePath(_))
+        ));
+    }
+    archive.add_file_from_slice(b"data", "a/..b").unwrap();
+    assert_eq!(entry_names(archive), ["a/..b"]);
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"data", "/a/../b").unwrap();
+    assert_eq!(entry_names(archive), ["a/../b"]);
+}
+
+#[cfg(feature = "zipcrypto")]
+#[test]
+fn zipcrypto_known_answer() {
+    // Entry encrypted by Info-ZIP's `zip -0 -P password`, with the plain header recovered by
+    // Python's `zipfile`. The header ends with a byte of the modification time, since Info-ZIP
+    // wrote a data descriptor.
+    let header = [
+        0x2F, 0x34, 0x2F, 0x29, 0x92, 0xA7, 0x72, 0xBB, 0xA7, 0xC2, 0xE2, 0x11,
+    ];
+    let encrypted = [
+        0xC1, 0x59, 0x38, 0xD8, 0x8E, 0x75, 0x4A, 0xCA, 0x92, 0x40, 0xBA, 0x5C, 0x8E, 0xFD, 0x8C,
+        0xDA, 0x38, 0xCF, 0x2C, 0x81, 0x08, 0xFE, 0x5B, 0x76, 0x81, 0x14, 0x00, 0xEA, 0x3E, 0x2F,
+        0x14, 0xD4, 0xBA, 0x76, 0x17, 0x58, 0xF3, 0xED,
+    ];
+    let password = crate::Password("password".to_string());
+    let mut output = Vec::new();
+    crate::zipcrypto::encrypt_with_header(
+        &b"Known answer for ZipCrypto"[..],
+        &mut output,
+        &password,
+        header,
+    )
+    .unwrap();
+    assert_eq!(output, encrypted);
+}
+
+#[test]
+fn dos_timestamps() {
+    // 2021-06-15 12:34:56 UTC
+    let time = UNIX_EPOCH + Duration::from_secs(1_623_760_496);
+    let dir = TempDir::new("dos-timestamps");
+    let path = dir.file("file", b"data");
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(time)
+        .unwrap();
+    let mut archive = ZipArchive::new_sequential();
+    archive
+        .add_file_from_slice_with_time(b"data", "slice", time)
+        .unwrap();
+    let before_1980 = UNIX_EPOCH + Duration::from_secs(157_766_400);
+    archive
+        .add_file_from_slice_with_time(b"data", "old", before_1980)
+        .unwrap();
+    archive.add_file_from_fs(&path, "fs").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    let date_time = |name| {
+        let entry = find(&entries, name);
+        assert_eq!(local_header(&bytes, entry)[10..14], entry.raw[12..16]);
+        (u16_at(&entry.raw, 14), u16_at(&entry.raw, 12))
+    };
+    let expected = (41 << 9 | 6 << 5 | 15, 12 << 11 | 34 << 5 | 28);
+    assert_eq!(date_time("slice"), expected);
+    assert_eq!(date_time("fs"), expected);
+    assert_eq!(date_time("old"), (1 << 5 | 1, 0));
+}
+
+#[test]
+fn duplicate_names() {
+    let mut archive = ZipArchive::with_global_pool();
+    archive.add_file_from_slice(b"first", "same").unwrap();
+    archive.add_file_from_slice(b"other", "other").unwrap();
+    archive.add_file_from_slice(b"second", "same").unwrap();
+    assert!(matches!(
+        archive.to_bytes(),
+        Err(ZipError::DuplicateName(name)) if name == "same"
+    ));
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_allow_duplicates(true);
+    archive.add_file_from_slice(b"first", "same").unwrap();
+    archive.add_file_from_slice(b"second", "same").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    assert_eq!(read_entries(&bytes).len(), 2);
+
+    let mut bytes = Cursor::new(bytes);
+    let mut archive = ZipArchive::append_with_global_pool(&mut bytes).unwrap();
+    archive.add_file_from_slice(b"third", "same").unwrap();
+    assert!(matches!(
+        archive.write(&mut bytes),
+        Err(ZipError::DuplicateName(name)) if name == "same"
+    ));
+}
+
+#[test]
+fn names_use_forward_slashes() {
+    let mut archive = ZipArchive::new_sequential();
+    archive
+        .add_file_from_slice(b"data", "docs\\readme.txt")
+        .unwrap();
+    archive.add_empty_file("/root\\empty").unwrap();
+    archive.add_directory("dir\\sub").unwrap();
+    archive.add_symlink("target", "\\\\link").unwrap();
+    assert_eq!(
+        entry_names(archive),
+        ["docs/readme.txt", "root/empty", "dir/sub/", "link"]
+    );
+}
+
+#[test]
+fn extended_timestamps() {
+    let modified = UNIX_EPOCH + Duration::from_secs(1_623_760_497);
+    let accessed = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let dir = TempDir::new("extended-timestamps");
+    let path = dir.file("file", b"data");
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_times(
+            std::fs::FileTimes::new()
+                .set_modified(modified)
+                .set_accessed(accessed),
+        )
+        .unwrap();
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"data", "without").unwrap();
+    archive.set_extended_timestamps(true);
+    archive
+        .add_file_from_slice_with_time(b"data", "slice", modified)
+        .unwrap();
+    archive.add_file_from_fs(&path, "fs").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    let fields = |name| {
+        let entry = find(&entries, name);
+        let local = local_extra(local_header(&bytes, entry));
+        (
+            extra_field(local, 0x5455).map(<[u8]>::to_vec),
+            extra_field(central_extra(&entry.raw), 0x5455).map(<[u8]>::to_vec),
+        )
+    };
+    assert_eq!(fields("without"), (None, None));
+
+    let mtime = 1_623_760_497u32.to_le_bytes();
+    let (local, central) = fields("slice");
+    assert_eq!(local.unwrap(), [&[1][..], &mtime].concat());
+    assert_eq!(central.unwrap(), [&[1][..], &mtime].concat());
+
+    let (local, central) = fields("fs");
+    let local = local.unwrap();
+    // The creation time depends on the filesystem
+    assert_eq!(local[0] & 3, 3);
+    assert_eq!(local[1..5], mtime);
+    assert_eq!(local[5..9], 1_700_000_000u32.to_le_bytes());
+    assert_eq!(central.unwrap(), [&[local[0]][..], &mtime].concat());
+}
+
+/// Unix mode in the external attributes of a raw central directory entry
+fn unix_mode(raw: &[u8]) -> u32 {
+    u32_at(raw, 38) >> 16
+}
+
+#[test]
+fn symlink_entry() {
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_symlink("../target", "link").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    let entry = find(&entries, "link");
+    assert_eq!(unix_mode(&entry.raw), 0o120777);
+    assert_eq!(entry.compression_method, 0);
+    assert_eq!(entry_data(&bytes, entry), b"../target");
+}
+
+#[cfg(unix)]
+#[test]
+fn recursive_symlink_handling() {
+    use crate::SymlinkHandling;
+
+    let dir = TempDir::new("symlink-handling");
+    dir.file("file", b"contents");
+    std::os::unix::fs::symlink("file", dir.path().join("link")).unwrap();
+    for (symlink_handling, link) in [
+        (SymlinkHandling::Skip, None),
+        (SymlinkHandling::Follow, Some((0o100000, &b"contents"[..]))),
+        (SymlinkHandling::Store, Some((0o120000, &b"file"[..]))),
+    ] {
+        let mut archive = ZipArchive::new_sequential();
+        archive.set_compression_type(CompressionType::Stored);
+        archive.set_symlink_handling(symlink_handling);
+        archive.add_directory_recursive(dir.path(), "").unwrap();
+        let bytes = archive.to_bytes().unwrap();
+        let entries = read_entries(&bytes);
+        assert_eq!(entries.len(), 1 + usize::from(link.is_some()));
+        if let Some((file_type, data)) = link {
+            let entry = find(&entries, "link");
+            assert_eq!(unix_mode(&entry.raw) & 0o170000, file_type);
+            assert_eq!(entry_data(&bytes, entry), data);
+        }
+    }
+}
+
+#[test]
+fn builder_applies_settings() {
+    let mut archive = ZipArchive::builder()
+        .compression(CompressionType::Stored)
+        .comment("built")
+        .extended_timestamps(true)
+        .name_sanitization(crate::NameSanitization::Strict)
+        .build()
+        .unwrap();
+    assert!(matches!(
+        archive.add_file_from_slice(b"data", "../escape"),
+        Err(ZipError::UnsafePath(_))
+    ));
+    archive.add_file_from_slice(&[0; 1000], "zeros").unwrap();
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    let central_directory = CentralDirectory::read(&mut bytes).unwrap();
+    assert_eq!(central_directory.comment, b"built");
+    let entry = &central_directory.entries[0];
+    assert_eq!(entry.compression_method, 0);
+    assert!(extra_field(central_extra(&entry.raw), 0x5455).is_some());
+
+    assert!(matches!(
+        ZipArchive::builder().level(10).build(),
+        Err(ZipError::InvalidCompressionLevel(10))
+    ));
+    assert!(matches!(
+        ZipArchive::builder()
+            .comment(&"x".repeat(u16::MAX as usize + 1))
+            .build(),
+        Err(ZipError::CommentTooLong)
+    ));
+}
+
+#[test]
+fn parallel_deflate_roundtrip() {
+    let mut rng = Rng(0xC0FFEE);
+    let data: Vec<u8> = (0..1_000_000)
+        .map(|_| b"abcdefgh"[rng.below(8) as usize])
+        .collect();
+    let dir = TempDir::new("parallel-deflate");
+    let path = dir.file("file", &data);
+    let compress = |chunk_size| {
+        let mut archive = ZipArchive::with_global_pool();
+        archive.set_parallel_deflate(chunk_size);
+        archive.add_file_from_slice(&data, "slice").unwrap();
+        archive.add_file_from_fs(&path, "fs").unwrap();
+        let built = archive.build().unwrap();
+        built.verify_roundtrip().unwrap();
+        built.to_bytes().unwrap()
+    };
+    let whole = compress(None);
+    let chunked = compress(Some(64 * 1024));
+    let mut crc = flate2::Crc::new();
+    crc.update(&data);
+    let chunked_entries = read_entries(&chunked);
+    let whole_entries = read_entries(&whole);
+    for name in ["slice", "fs"] {
+        let entry = find(&chunked_entries, name);
+        assert_eq!(entry.compression_method, 8);
+        assert_eq!(entry.crc, crc.sum());
+        assert_ne!(
+            entry_data(&chunked, entry),
+            entry_data(&whole, find(&whole_entries, name))
+        );
+        let extracted = ZipArchive::extract_entry(&mut Cursor::new(&chunked), name).unwrap();
+        assert!(extracted == data);
+    }
+}
+
+#[cfg(feature = "memmap2")]
+#[test]
+fn memory_mapped_files() {
+    let data = Rng(0xAB).bytes(300_000);
+    let dir = TempDir::new("memory-map");
+    let path = dir.file("file", &data);
+    let empty = dir.file("empty", b"");
+    let mut archive = ZipArchive::with_global_pool();
+    archive.set_memory_map(true);
+    archive.set_auto_store(true);
+    archive.add_file_from_fs(&path, "file").unwrap();
+    archive.add_file_from_fs(&empty, "empty").unwrap();
+    archive.set_compression_type(CompressionType::Stored);
+    archive.add_file_from_fs(&path, "stored").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    assert_eq!(find(&entries, "empty").uncompressed_size, 0);
+    for name in ["file", "stored"] {
+        let entry = find(&entries, name);
+        assert_eq!(entry.compression_method, 0);
+        assert!(entry_data(&bytes, entry) == data);
+    }
+    let mut bytes = Cursor::new(bytes);
+    assert!(ZipArchive::extract_entry(&mut bytes, "empty")
+        .unwrap()
+        .is_empty());
+    assert!(ZipArchive::extract_entry(&mut bytes, "file").unwrap() == data);
+}
+
+#[test]
+fn duplicate_contents_report() {
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"shared", "old").unwrap();
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    let mut archive = ZipArchive::append_with_global_pool(&mut bytes).unwrap();
+    archive.set_entry_order(crate::EntryOrder::Insertion);
+    archive.add_file_from_slice(b"unique", "unique").unwrap();
+    archive.set_compression_type(CompressionType::Stored);
+    archive.add_file_from_slice(b"shared", "stored").unwrap();
+    archive.add_empty_file("empty").unwrap();
+    archive.add_empty_file("also empty").unwrap();
+    let built = archive.build().unwrap();
+    let report = built.duplicate_report();
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].names, ["old", "stored"]);
+    assert_eq!(report[0].uncompressed_size, 6);
+    let mut crc = flate2::Crc::new();
+    crc.update(b"shared");
+    assert_eq!(report[0].crc, crc.sum());
+}
+
+#[test]
+fn reproducible_archives() {
+    let dir = TempDir::new("reproducible");
+    let plain = dir.file("plain", b"plain file");
+    let script = dir.file("script", b"#!/bin/sh");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&plain, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o700)).unwrap();
+    }
+    let build = |modified| {
+        File::options()
+            .write(true)
+            .open(&plain)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let mut archive = ZipArchive::with_global_pool();
+        archive.set_reproducible(true);
+        for index in 0..20 {
+            archive
+                .add_file_from_slice(&vec![index; 10_000], &format!("file{index}"))
+                .unwrap();
+        }
+        archive.add_file_from_fs(&plain, "plain").unwrap();
+        archive.add_file_from_fs(&script, "script").unwrap();
+        archive.add_directory("dir").unwrap();
+        archive.to_bytes().unwrap()
+    };
+    let bytes = build(UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+    assert_eq!(
+        bytes,
+        build(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+    );
+
+    let entries = read_entries(&bytes);
+    let names: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.filename.as_str())
+        .collect();
+    assert_eq!(names[..2], ["file0", "file1"]);
+    assert_eq!(names[20..], ["plain", "script", "dir/"]);
+    for entry in &entries {
+        // 1980-01-01 00:00:00
+        assert_eq!(u16_at(&entry.raw, 14), 1 << 5 | 1);
+        assert_eq!(u16_at(&entry.raw, 12), 0);
+    }
+    #[cfg(unix)]
+    {
+        assert_eq!(unix_mode(&find(&entries, "plain").raw), 0o100644);
+        assert_eq!(unix_mode(&find(&entries, "script").raw), 0o100755);
+    }
+}
+
+/// External attributes of the entries of an archive with a directory, a file, a read-only file
+/// and a file with MS-DOS attributes of its own, after `configure` is applied
+fn external_attributes(configure: impl FnOnce(&mut ZipArchive)) -> [u32; 4] {
+    let mut archive = ZipArchive::new_sequential();
+    configure(&mut archive);
+    archive.add_directory("dir").unwrap();
+    archive.add_file_from_slice(b"data", "file").unwrap();
+    let read_only = crate::FileOptions::new().external_attributes(0o100444 << 16);
+    archive
+        .add_file_from_slice_with(b"data", "read-only", read_only)
+        .unwrap();
+    let hidden = crate::FileOptions::new().external_attributes(0o100644 << 16 | 0x02);
+    archive
+        .add_file_from_slice_with(b"data", "hidden", hidden)
+        .unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    ["dir/", "file", "read-only", "hidden"].map(|name| u32_at(&find(&entries, name).raw, 38))
+}
+
+#[test]
+fn dos_attributes() {
+    assert_eq!(
+        external_attributes(|_| {}),
+        [
+            0o40755 << 16,
+            0o100644 << 16,
+            0o100444 << 16,
+            0o100644 << 16 | 0x02
+        ]
+    );
+    assert_eq!(
+        external_attributes(|archive| archive.set_dos_attributes(true)),
+        [
+            0o40755 << 16 | 0x10,
+            0o100644 << 16 | 0x20,
+            0o100444 << 16 | 0x21,
+            0o100644 << 16 | 0x02
+        ]
+    );
+}
+
+/// Reader of zeros that cancels the archive once `cancel_after` bytes are read and counts how
+/// many bytes were read in total
+struct CancellingReader {
+    token: crate::CancelToken,
+    cancel_after: usize,
+    read: Arc<AtomicUsize>,
+}
+
+impl std::io::Read for CancellingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read.fetch_add(buf.len(), Ordering::Relaxed) + buf.len();
+        if read >= self.cancel_after {
+            self.token.cancel();
+        }
+        // Large enough to take a long time if cancelling didn't stop the reads
+        if read > 1 << 30 {
+            return Ok(0);
+        }
+        buf.fill(0);
+        Ok(buf.len())
+    }
+}
+
+#[test]
+fn cancel_stops_compression() {
+    let mut archive = ZipArchive::with_global_pool();
+    let read = Arc::new(AtomicUsize::new(0));
+    archive.add_file_from_slice(b"data", "before").unwrap();
+    archive
+        .add_file_from_reader(
+            CancellingReader {
+                token: archive.cancel_token(),
+                cancel_after: 1 << 20,
+                read: read.clone(),
+            },
+            "cancelling",
+        )
+        .unwrap();
+    assert!(matches!(archive.to_bytes(), Err(ZipError::Cancelled)));
+    assert!(read.load(Ordering::Relaxed) < 2 << 20);
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.cancel_token().cancel();
+    archive.add_file_from_slice(b"data", "after").unwrap();
+    assert!(matches!(
+        archive.write_incremental(&mut Vec::new()),
+        Err(ZipError::Cancelled)
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_owner_extra_field() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = TempDir::new("unix-owner");
+    let path = dir.file("file", b"data");
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    let metadata = std::fs::metadata(&path).unwrap();
+    let mut expected = vec![1, 4];
+    expected.extend_from_slice(&metadata.uid().to_le_bytes());
+    expected.push(4);
+    expected.extend_from_slice(&metadata.gid().to_le_bytes());
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_fs(&path, "without").unwrap();
+    archive.set_preserve_ownership(true);
+    archive.add_file_from_fs(&path, "file").unwrap();
+    archive
+        .add_directory_from_fs(&dir.path().join("sub"), "sub")
+        .unwrap();
+    archive.add_file_from_slice(b"data", "slice").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    let owner = |name| {
+        let entry = find(&entries, name);
+        let local = extra_field(local_extra(local_header(&bytes, entry)), 0x7875);
+        let central = extra_field(central_extra(&entry.raw), 0x7875);
+        assert_eq!(local, central);
+        central.map(<[u8]>::to_vec)
+    };
+    assert_eq!(owner("without"), None);
+    assert_eq!(owner("slice"), None);
+    assert_eq!(owner("file").unwrap(), expected);
+    assert_eq!(owner("sub/").unwrap(), expected);
+}
+
+#[test]
+fn incremental_writing() {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let mut archive = ZipArchive::with_capacity(&pool, 2);
+    let contents: Vec<_> = (0..20u8).map(|index| vec![index; 5000]).collect();
+    for (index, data) in contents.iter().enumerate() {
+        archive
+            .add_file_from_slice(data, &format!("file{index}"))
+            .unwrap();
+    }
+    let mut bytes = Vec::new();
+    let stats = archive.write_incremental(&mut bytes).unwrap();
+    assert_eq!(stats.entries, 20);
+    assert_eq!(stats.bytes_written, bytes.len() as u64);
+    assert_eq!(stats.end_offset, bytes.len() as u64);
+    assert_eq!(read_entries(&bytes).len(), 20);
+    let mut bytes = Cursor::new(bytes);
+    for (index, data) in contents.iter().enumerate() {
+        let name = format!("file{index}");
+        assert_eq!(&ZipArchive::extract_entry(&mut bytes, &name).unwrap(), data);
+    }
+
+    let mut archive = ZipArchive::append_with_global_pool(&mut bytes).unwrap();
+    archive
+        .add_file_from_slice(b"appended", "appended")
+        .unwrap();
+    let stats = archive.write_incremental(&mut bytes).unwrap();
+    let mut bytes = bytes.into_inner();
+    bytes.truncate(stats.end_offset as usize);
+    assert_eq!(read_entries(&bytes).len(), 21);
+    assert_eq!(
+        ZipArchive::extract_entry(&mut Cursor::new(bytes), "appended").unwrap(),
+        b"appended"
+    );
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"first", "same").unwrap();
+    archive.add_file_from_slice(b"second", "same").unwrap();
+    assert!(matches!(
+        archive.write_incremental(&mut Vec::new()),
+        Err(ZipError::DuplicateName(_))
+    ));
+}
+
+#[test]
+fn empty_names_are_rejected() {
+    let mut archive = ZipArchive::new_sequential();
+    for name in ["", "   ", "/", "\\\\"] {
+        assert!(matches!(
+            archive.add_file_from_slice(b"data", name),
+            Err(ZipError::EmptyName)
+        ));
+        assert!(matches!(
+            archive.add_directory(name),
+            Err(ZipError::EmptyName)
+        ));
+    }
+    assert!(matches!(
+        archive.add_symlink("target", ""),
+        Err(ZipError::EmptyName)
+    ));
+    archive.add_file_from_slice(b"data", " name ").unwrap();
+    assert_eq!(entry_names(archive), [" name "]);
+}
+
+#[test]
+fn entry_filter_prunes_recursive_adds() {
+    let dir = TempDir::new("entry-filter");
+    std::fs::create_dir_all(dir.path().join(".git/objects")).unwrap();
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    dir.file(".git/objects/blob", b"blob");
+    dir.file("src/main.rs", b"fn main() {}");
+    dir.file("small", b"small");
+    dir.file("large", &[0; 10_000]);
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_entry_order(crate::EntryOrder::Name);
+    archive.set_entry_filter(|path, metadata| {
+        path.file_name().unwrap() != ".git" && (metadata.is_dir() || metadata.len() < 5000)
+    });
+    archive.add_directory_recursive(dir.path(), "root").unwrap();
+    assert_eq!(
+        entry_names(archive),
+        ["root/", "root/small", "root/src/", "root/src/main.rs"]
+    );
+}
+
+#[test]
+fn absorb_merges_built_archives() {
+    let mut part = ZipArchive::with_global_pool();
+    part.add_file_from_slice(b"part one", "one").unwrap();
+    part.add_file_from_slice(b"part two", "two").unwrap();
+    let part = part.build().unwrap();
+    let mut archive = ZipArchive::new_sequential();
+    archive.set_entry_order(crate::EntryOrder::Insertion);
+    let first = archive.add_file_from_slice(b"own", "own").unwrap();
+    let ids = archive.absorb(part).unwrap();
+    assert_eq!(ids.len(), 2);
+    assert!(ids.iter().all(|&id| id > first));
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    let mut names: Vec<_> = read_entries(bytes.get_ref())
+        .into_iter()
+        .map(|entry| entry.filename)
+        .collect();
+    names[1..].sort();
+    assert_eq!(names, ["own", "one", "two"]);
+    assert_eq!(
+        ZipArchive::extract_entry(&mut bytes, "two").unwrap(),
+        b"part two"
+    );
+
+    let mut part = ZipArchive::new_sequential();
+    part.add_file_from_slice(b"other", "own").unwrap();
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"own", "own").unwrap();
+    archive.absorb(part.build().unwrap()).unwrap();
+    assert!(matches!(
+        archive.to_bytes(),
+        Err(ZipError::DuplicateName(name)) if name == "own"
+    ));
+
+    let mut appended = ZipArchive::append_with_global_pool(&mut bytes).unwrap();
+    appended.add_file_from_slice(b"new", "new").unwrap();
+    let mut archive = ZipArchive::new_sequential();
+    assert!(matches!(
+        archive.absorb(appended.build().unwrap()),
+        Err(ZipError::CannotAbsorb(_))
+    ));
+}
+
+#[test]
+fn directory_entries() {
+    // 2021-06-15 12:34:56 UTC
+    let modified = UNIX_EPOCH + Duration::from_secs(1_623_760_496);
+    let dir = TempDir::new("directory-entries");
+    let fs_dir = dir.path().join("fs");
+    std::fs::create_dir(&fs_dir).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&fs_dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+    }
+    File::open(&fs_dir).unwrap().set_modified(modified).unwrap();
+
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_directory("a//b").unwrap();
+    archive.add_directory("c///").unwrap();
+    archive.add_directory("d\\\\e/").unwrap();
+    archive.add_directory_from_fs(&fs_dir, "from//fs").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    let names: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.filename.as_str())
+        .collect();
+    assert_eq!(names, ["a/b/", "c/", "d/e/", "from/fs/"]);
+    for entry in &entries {
+        assert_eq!(entry.compressed_size, 0);
+        assert_eq!(unix_mode(&entry.raw) & 0o170000, 0o040000);
+    }
+    let from_fs = find(&entries, "from/fs/");
+    assert_eq!(u16_at(&from_fs.raw, 14), 41 << 9 | 6 << 5 | 15);
+    assert_eq!(u16_at(&from_fs.raw, 12), 12 << 11 | 34 << 5 | 28);
+    #[cfg(unix)]
+    assert_eq!(unix_mode(&from_fs.raw), 0o40700);
+}
+
+#[test]
+fn attribute_modes() {
+    use crate::AttributeMode;
+
+    assert_eq!(
+        external_attributes(|archive| { archive.set_external_attributes_mode(AttributeMode::Dos) }),
+        [0x10, 0x20, 0x21, 0x02]
+    );
+    assert_eq!(
+        external_attributes(|archive| {
+            archive.set_external_attributes_mode(AttributeMode::Zero)
+        }),
+        [0; 4]
+    );
+
+    let mut bytes = Cursor::new(Vec::new());
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_slice(b"data", "old").unwrap();
+    archive.write(&mut bytes).unwrap();
+    let mut archive = ZipArchive::append_with_global_pool(&mut bytes).unwrap();
+    archive.set_external_attributes_mode(AttributeMode::Zero);
+    archive.add_file_from_slice(b"data", "new").unwrap();
+    archive.write(&mut bytes).unwrap();
+    let entries = read_entries(bytes.get_ref());
+    assert_eq!(u32_at(&find(&entries, "old").raw, 38), 0o100644 << 16);
+    assert_eq!(u32_at(&find(&entries, "new").raw, 38), 0);
+}
+
+/// Reader that counts how many readers of the same `active` counter are being read at once
+struct ConcurrencyReader {
+    data: Cursor<Vec<u8>>,
+    started: bool,
+    finished: bool,
+    active: Arc<AtomicUsize>,
+    max_active: Arc<AtomicUsize>,
+}
+
+impl std::io::Read for ConcurrencyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.started {
+            self.started = true;
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_active.fetch_max(active, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let read = self.data.read(buf)?;
+        // Readers can be read again after they returned the end
+        if read == 0 && !self.finished {
+            self.finished = true;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(read)
+    }
+}
+
+#[test]
+fn max_compression_threads() {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(4)
+        .build()
+        .unwrap();
+    let mut archive = ZipArchive::new(&pool);
+    archive.set_max_compression_threads(Some(2));
+    let active = Arc::new(AtomicUsize::new(0));
+    let max_active = Arc::new(AtomicUsize::new(0));
+    for index in 0..12u8 {
+        let reader = ConcurrencyReader {
+            data: Cursor::new(vec![index; 1000]),
+            started: false,
+            finished: false,
+            active: active.clone(),
+            max_active: max_active.clone(),
+        };
+        archive
+            .add_file_from_reader(reader, &format!("file{index}"))
+            .unwrap();
+    }
+    let mut bytes = Cursor::new(archive.to_bytes().unwrap());
+    assert!((1..=2).contains(&max_active.load(Ordering::SeqCst)));
+    assert_eq!(read_entries(bytes.get_ref()).len(), 12);
+    for index in 0..12u8 {
+        let name = format!("file{index}");
+        assert_eq!(
+            ZipArchive::extract_entry(&mut bytes, &name).unwrap(),
+            vec![index; 1000]
+        );
+    }
+}
+
+#[test]
+fn ntfs_timestamps() {
+    let modified = UNIX_EPOCH + Duration::new(1_623_760_496, 123_456_789);
+    let accessed = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let dir = TempDir::new("ntfs-timestamps");
+    let path = dir.file("file", b"data");
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_times(
+            std::fs::FileTimes::new()
+                .set_modified(modified)
+                .set_accessed(accessed),
+        )
+        .unwrap();
+    let mut archive = ZipArchive::new_sequential();
+    archive.add_file_from_fs(&path, "without").unwrap();
+    archive.set_ntfs_timestamps(true);
+    archive.add_file_from_fs(&path, "file").unwrap();
+    archive.add_directory_from_fs(dir.path(), "dir").unwrap();
+    archive.add_file_from_slice(b"data", "slice").unwrap();
+    let bytes = archive.to_bytes().unwrap();
+    let entries = read_entries(&bytes);
+    let field = |name| {
+        let entry = find(&entries, name);
+        let local = extra_field(local_extra(local_header(&bytes, entry)), 0x000A);
+        let central = extra_field(central_extra(&entry.raw), 0x000A);
+        assert_eq!(local, central);
+        central.map(<[u8]>::to_vec)
+    };
+    assert_eq!(field("without"), None);
+    assert_eq!(field("slice"), None);
+    assert_eq!(field("dir/").unwrap().len(), 32);
+
+    let field = field("file").unwrap();
+    let filetime = |at: usize| u64::from_le_bytes(field[at..at + 8].try_into().unwrap());
+    assert_eq!(field.len(), 32);
+    // Reserved, then the timestamps attribute with a size of 24
+    assert_eq!(field[..8], [0, 0, 0, 0, 1, 0, 24, 0]);
+    assert_eq!(
+        filetime(8),
+        (1_623_760_496 + 11_644_473_600) * 10_000_000 + 1_234_567
+    );
+    // Reading the file for the first entry can update its access time
+    assert!(filetime(16) >= (1_700_000_000 + 11_644_473_600) * 10_000_000);
+    assert_ne!(filetime(24), 0);
+}
@@ -0,0 +1,107 @@
+//! Traditional PKWARE encryption, also known as ZipCrypto. It's a weak stream cipher keyed by the
+//! password, which is only useful for compatibility with extractors that don't support anything
+//! else.
+
+use std::io::{Read, Write};
+
+use crate::Password;
+
+/// Length of the encryption header in front of the encrypted data
+pub(crate) const HEADER_LEN: usize = 12;
+
+/// CRC-32 lookup table, the key schedule updates keys one byte at a time
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32_byte(crc: u32, byte: u8) -> u32 {
+    crc >> 8 ^ CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize]
+}
+
+struct Keys([u32; 3]);
+
+impl Keys {
+    fn new(password: &Password) -> Self {
+        let mut keys = Self([0x12345678, 0x23456789, 0x34567890]);
+        for &byte in password.0.as_bytes() {
+            keys.update(byte);
+        }
+        keys
+    }
+
+    fn update(&mut self, byte: u8) {
+        let [k0, k1, k2] = &mut self.0;
+        *k0 = crc32_byte(*k0, byte);
+        *k1 = k1
+            .wrapping_add(*k0 & 0xFF)
+            .wrapping_mul(134775813)
+            .wrapping_add(1);
+        *k2 = crc32_byte(*k2, (*k1 >> 24) as u8);
+    }
+
+    fn encrypt_byte(&mut self, byte: u8) -> u8 {
+        let temp = (self.0[2] | 2) & 0xFFFF;
+        let key_byte = (temp.wrapping_mul(temp ^ 1) >> 8) as u8;
+        self.update(byte);
+        byte ^ key_byte
+    }
+}
+
+/// Encrypt compressed data from `reader` into `output`, after an encryption header. The last byte
+/// of the header is the high byte of the CRC, which extractors use to check the password.
+pub(crate) fn encrypt<R: Read, W: Write + ?Sized>(
+    reader: R,
+    output: &mut W,
+    password: &Password,
+    crc: u32,
+) -> Result<(), std::io::Error> {
+    let mut header = [0; HEADER_LEN];
+    getrandom::fill(&mut header[..HEADER_LEN - 1])?;
+    header[HEADER_LEN - 1] = (crc >> 24) as u8;
+    encrypt_with_header(reader, output, password, header)
+}
+
+/// Encrypt `header` and then compressed data from `reader` into `output`, the part of
+/// [`encrypt`] that doesn't depend on random bytes
+pub(crate) fn encrypt_with_header<R: Read, W: Write + ?Sized>(
+    mut reader: R,
+    output: &mut W,
+    password: &Password,
+    mut header: [u8; HEADER_LEN],
+) -> Result<(), std::io::Error> {
+    let mut keys = Keys::new(password);
+    for byte in &mut header {
+        *byte = keys.encrypt_byte(*byte);
+    }
+    output.write_all(&header)?;
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for byte in &mut buf[..read] {
+            *byte = keys.encrypt_byte(*byte);
+        }
+        output.write_all(&buf[..read])?;
+    }
+}